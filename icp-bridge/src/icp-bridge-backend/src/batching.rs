@@ -0,0 +1,189 @@
+use crate::config;
+use crate::error::BridgeError;
+use crate::merkle::{self, MerkleTree};
+use crate::solana_pda;
+use crate::threshold_schnorr;
+use crate::tickets::{self, Ticket};
+use candid::CandidType;
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Keeps proofs at no more than 8 hashes.
+const MAX_BATCH_SIZE: usize = 256;
+
+/// A set of tickets authorised by a single signature over their Merkle root.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Batch {
+    pub id: u64,
+    pub root_hex: String,
+    pub signature_hex: String,
+    pub key_epoch: u32,
+    pub ticket_ids: Vec<u64>,
+    pub signed_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct BatchState {
+    pub next_id: u64,
+    pub batches: BTreeMap<u64, Batch>,
+}
+
+/// Everything a relayer needs to redeem one ticket with `withdraw_batch`.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TicketProof {
+    pub ticket_id: u64,
+    pub amount: u64,
+    pub mint: String,
+    pub recipient: String,
+    /// Set when `withdraw_batch` should create `recipient` as this wallet's
+    /// associated token account if it does not exist.
+    pub recipient_wallet: Option<String>,
+    /// Redeemed with `withdraw_sol` rather than `withdraw_batch`; the leaf then
+    /// hashes `NATIVE_SOL` in place of `mint`.
+    pub native_sol: bool,
+    pub expiry: i64,
+    pub batch_id: u64,
+    pub root_hex: String,
+    pub signature_hex: String,
+    pub proof: Vec<String>,
+}
+
+thread_local! {
+    static BATCHES: RefCell<BatchState> = RefCell::new(BatchState::default());
+    static SIGNING: Cell<bool> = Cell::new(false);
+}
+
+// Keeps timer ticks from signing the same queued tickets twice while a
+// `sign_with_schnorr` call is outstanding; released on drop like `PrincipalGuard`.
+struct SigningLock;
+
+impl SigningLock {
+    fn acquire() -> Option<Self> {
+        if SIGNING.with(|s| s.replace(true)) {
+            None
+        } else {
+            Some(SigningLock)
+        }
+    }
+}
+
+impl Drop for SigningLock {
+    fn drop(&mut self) {
+        SIGNING.with(|s| s.set(false));
+    }
+}
+
+/// Signs the Merkle root over the oldest queued tickets; run by the scheduler's
+/// `SignBatches` task. On failure the tickets stay queued for the next run.
+pub async fn sign_queued_tickets() -> Result<Option<Batch>, BridgeError> {
+    if crate::check_not_paused().is_err() {
+        return Ok(None);
+    }
+    let Some(_lock) = SigningLock::acquire() else {
+        return Ok(None);
+    };
+    let queued = tickets::queued(MAX_BATCH_SIZE, tickets::now_seconds());
+    if queued.is_empty() {
+        return Ok(None);
+    }
+
+    let leaves = queued
+        .iter()
+        .map(ticket_leaf)
+        .collect::<Result<Vec<_>, BridgeError>>()?;
+    let tree = MerkleTree::new(leaves);
+    let root = tree.root();
+    let key_epoch = threshold_schnorr::current_epoch();
+    let signature = threshold_schnorr::schnorr_sign(merkle::batch_message(&root)).await?;
+
+    let batch = BATCHES.with(|b| {
+        let mut state = b.borrow_mut();
+        let batch = Batch {
+            id: state.next_id,
+            root_hex: hex::encode(root),
+            signature_hex: signature.signature_hex,
+            key_epoch,
+            ticket_ids: queued.iter().map(|t| t.id).collect(),
+            signed_at: ic_cdk::api::time(),
+        };
+        state.next_id += 1;
+        state.batches.insert(batch.id, batch.clone());
+        batch
+    });
+    for (index, ticket) in queued.iter().enumerate() {
+        let proof = tree.proof(index).iter().map(hex::encode).collect();
+        tickets::mark_batched(ticket.id, batch.id, &batch.signature_hex, proof);
+    }
+    Ok(Some(batch))
+}
+
+fn ticket_leaf(ticket: &Ticket) -> Result<[u8; 32], BridgeError> {
+    let mint = if ticket.pays_native_sol() {
+        merkle::NATIVE_SOL
+    } else {
+        solana_pda::decode_pubkey(&ticket_mint(ticket)?).map_err(BridgeError::InvalidConfig)?
+    };
+    let recipient = solana_pda::decode_pubkey(&ticket.recipient).map_err(BridgeError::InvalidAddress)?;
+    Ok(merkle::withdrawal_leaf(ticket.id, ticket.amount, &mint, &recipient, ticket.expiry))
+}
+
+/// Solana mint a ticket is paid in: its wrapped token's, or the pool token's.
+fn ticket_mint(ticket: &Ticket) -> Result<String, BridgeError> {
+    match &ticket.wrapped_mint {
+        Some(mint) => Ok(mint.clone()),
+        None => config::read_config(|c| c.solana_token_mint.clone())
+            .ok_or_else(|| BridgeError::InvalidConfig("solana_token_mint is not configured".to_string())),
+    }
+}
+
+/// Drops the batches among `batch_ids` whose tickets have all been pruned.
+pub fn prune_batches(batch_ids: &BTreeSet<u64>) {
+    BATCHES.with(|b| {
+        b.borrow_mut().batches.retain(|id, batch| {
+            !batch_ids.contains(id) || batch.ticket_ids.iter().any(|ticket_id| tickets::exists(*ticket_id))
+        })
+    });
+}
+
+pub(crate) fn take_batch_state() -> BatchState {
+    BATCHES.with(|b| b.take())
+}
+
+pub(crate) fn restore_batch_state(state: BatchState) {
+    BATCHES.with(|b| *b.borrow_mut() = state);
+}
+
+#[ic_cdk::query]
+fn get_batch(id: u64) -> Option<Batch> {
+    BATCHES.with(|b| b.borrow().batches.get(&id).cloned())
+}
+
+#[ic_cdk::query]
+fn get_ticket_proof(id: u64) -> Result<TicketProof, BridgeError> {
+    let ticket = tickets::get(id).ok_or_else(|| BridgeError::NotFound(format!("Ticket {} not found", id)))?;
+    let (batch_id, proof) = match (ticket.batch_id, ticket.merkle_proof) {
+        (Some(batch_id), Some(proof)) => (batch_id, proof),
+        _ => {
+            return Err(BridgeError::InvalidState(format!(
+                "Ticket {} is not part of a signed batch yet",
+                id
+            )))
+        }
+    };
+    let batch = get_batch(batch_id).ok_or_else(|| BridgeError::NotFound(format!("Batch {} not found", batch_id)))?;
+    let mint = ticket_mint(&ticket)?;
+    Ok(TicketProof {
+        ticket_id: ticket.id,
+        amount: ticket.amount,
+        mint,
+        recipient: ticket.recipient,
+        recipient_wallet: ticket.recipient_wallet,
+        native_sol: ticket.pays_native_sol(),
+        expiry: ticket.expiry,
+        batch_id,
+        root_hex: batch.root_hex,
+        signature_hex: batch.signature_hex,
+        proof,
+    })
+}
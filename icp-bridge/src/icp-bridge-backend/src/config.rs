@@ -0,0 +1,176 @@
+use crate::solana_rpc::{self, HttpOutcallTransport, SolanaRpcClient};
+use crate::threshold_schnorr::SchnorrKeyId;
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use std::cell::RefCell;
+
+/// Argument of both `init` and `post_upgrade`, so a single candid type can be
+/// declared for the service.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum BridgeArg {
+    Init(InitArgs),
+    Upgrade(Option<UpgradeArgs>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SolanaCluster {
+    Mainnet,
+    Devnet,
+    Testnet,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct InitArgs {
+    pub schnorr_key_id: SchnorrKeyId,
+    pub ledger_id: Principal,
+    pub solana_cluster: SolanaCluster,
+    pub solana_program_id: String,
+    /// Solana mint of the bridged ICP token, required by `bridge_to_solana`.
+    pub solana_token_mint: Option<String>,
+    /// Overrides the cluster's default public RPC endpoints.
+    pub solana_rpc_providers: Option<Vec<String>>,
+    /// How long a withdrawal signature stays valid on Solana, defaults to a day.
+    pub ticket_ttl_seconds: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UpgradeArgs {
+    pub schnorr_key_id: Option<SchnorrKeyId>,
+    pub ledger_id: Option<Principal>,
+    pub solana_cluster: Option<SolanaCluster>,
+    pub solana_program_id: Option<String>,
+    pub solana_token_mint: Option<String>,
+    pub solana_rpc_providers: Option<Vec<String>>,
+    pub ticket_ttl_seconds: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Config {
+    pub schnorr_key_id: SchnorrKeyId,
+    pub ledger_id: Principal,
+    pub solana_cluster: SolanaCluster,
+    pub solana_program_id: String,
+    pub solana_token_mint: Option<String>,
+    pub solana_rpc_providers: Vec<String>,
+    pub ticket_ttl_seconds: u64,
+}
+
+const DEFAULT_TICKET_TTL_SECONDS: u64 = 24 * 60 * 60;
+const MIN_TICKET_TTL_SECONDS: u64 = 10 * 60;
+const MAX_TICKET_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+thread_local! {
+    static CONFIG: RefCell<Option<Config>> = RefCell::new(None);
+}
+
+impl SolanaCluster {
+    fn default_rpc_providers(&self) -> Vec<String> {
+        let providers = match self {
+            SolanaCluster::Mainnet => solana_rpc::MAINNET_PROVIDERS,
+            SolanaCluster::Devnet => solana_rpc::DEVNET_PROVIDERS,
+            SolanaCluster::Testnet => solana_rpc::TESTNET_PROVIDERS,
+        };
+        providers.iter().map(|p| p.to_string()).collect()
+    }
+}
+
+impl Config {
+    pub fn from_init_args(args: InitArgs) -> Result<Self, String> {
+        let solana_rpc_providers = args
+            .solana_rpc_providers
+            .unwrap_or_else(|| args.solana_cluster.default_rpc_providers());
+        let config = Config {
+            schnorr_key_id: args.schnorr_key_id,
+            ledger_id: args.ledger_id,
+            solana_cluster: args.solana_cluster,
+            solana_program_id: args.solana_program_id,
+            solana_token_mint: args.solana_token_mint,
+            solana_rpc_providers,
+            ticket_ttl_seconds: args.ticket_ttl_seconds.unwrap_or(DEFAULT_TICKET_TTL_SECONDS),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn apply_upgrade_args(&mut self, args: UpgradeArgs) -> Result<(), String> {
+        let mut updated = self.clone();
+        // the key id moves with the rotation epoch, so it only changes through a key rotation
+        if args.schnorr_key_id.is_some_and(|key_id| key_id != self.schnorr_key_id) {
+            return Err("schnorr_key_id can only be changed with prepare_bridge_key_rotation".to_string());
+        }
+        if let Some(ledger_id) = args.ledger_id {
+            updated.ledger_id = ledger_id;
+        }
+        if let Some(cluster) = args.solana_cluster {
+            // switching cluster without explicit providers means its default endpoints
+            if cluster != updated.solana_cluster && args.solana_rpc_providers.is_none() {
+                updated.solana_rpc_providers = cluster.default_rpc_providers();
+            }
+            updated.solana_cluster = cluster;
+        }
+        if let Some(program_id) = args.solana_program_id {
+            updated.solana_program_id = program_id;
+        }
+        if let Some(mint) = args.solana_token_mint {
+            updated.solana_token_mint = Some(mint);
+        }
+        if let Some(providers) = args.solana_rpc_providers {
+            updated.solana_rpc_providers = providers;
+        }
+        if let Some(ttl) = args.ticket_ttl_seconds {
+            updated.ticket_ttl_seconds = ttl;
+        }
+        updated.validate()?;
+        *self = updated;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.schnorr_key_id.validate()?;
+        if self.ledger_id == Principal::anonymous() || self.ledger_id == Principal::management_canister() {
+            return Err(format!("ledger_id {} is not a ledger canister", self.ledger_id));
+        }
+        let program_id = bs58::decode(&self.solana_program_id)
+            .into_vec()
+            .map_err(|e| format!("solana_program_id is not base58: {}", e))?;
+        if program_id.len() != 32 {
+            return Err("solana_program_id must decode to 32 bytes".to_string());
+        }
+        if let Some(mint) = &self.solana_token_mint {
+            crate::solana_pda::decode_pubkey(mint).map_err(|e| format!("solana_token_mint: {}", e))?;
+        }
+        if self.solana_rpc_providers.is_empty() {
+            return Err("at least one Solana RPC provider is required".to_string());
+        }
+        if let Some(provider) = self.solana_rpc_providers.iter().find(|p| !p.starts_with("https://")) {
+            return Err(format!("Solana RPC provider {} must use https", provider));
+        }
+        if !(MIN_TICKET_TTL_SECONDS..=MAX_TICKET_TTL_SECONDS).contains(&self.ticket_ttl_seconds) {
+            return Err(format!(
+                "ticket_ttl_seconds must be between {} and {}",
+                MIN_TICKET_TTL_SECONDS, MAX_TICKET_TTL_SECONDS
+            ));
+        }
+        Ok(())
+    }
+
+    /// RPC client requiring a strict majority of the configured providers to agree.
+    pub fn solana_rpc_client(&self) -> SolanaRpcClient<HttpOutcallTransport> {
+        let quorum = self.solana_rpc_providers.len() / 2 + 1;
+        SolanaRpcClient::new(self.solana_rpc_providers.clone(), quorum)
+    }
+}
+
+pub fn set_config(config: Config) {
+    CONFIG.with(|c| *c.borrow_mut() = Some(config));
+}
+
+/// Panics if called before `init`/`post_upgrade` installed a config.
+pub fn read_config<R>(f: impl FnOnce(&Config) -> R) -> R {
+    CONFIG.with(|c| f(c.borrow().as_ref().expect("bridge config is not initialized")))
+}
+
+#[ic_cdk::query]
+fn get_config() -> Config {
+    read_config(|c| c.clone())
+}
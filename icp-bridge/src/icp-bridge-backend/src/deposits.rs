@@ -0,0 +1,176 @@
+use crate::config::read_config;
+use crate::error::BridgeError;
+use crate::guard::PrincipalGuard;
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::call::call;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Each sweep costs two ledger calls per user.
+const MAX_SWEEPS_PER_RUN: usize = 20;
+
+/// A sweep whose reply was lost. It is resent unchanged on the next pass, so
+/// the ledger reports a duplicate if it went through and the user is credited once.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingSweep {
+    pub args: TransferArg,
+    pub credited: u64,
+}
+
+thread_local! {
+    // users who asked for a deposit subaccount, i.e. the ones worth sweeping
+    static DEPOSITORS: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::new());
+    static SWEEP_CURSOR: RefCell<Option<Principal>> = RefCell::new(None);
+    static PENDING_SWEEPS: RefCell<BTreeMap<Principal, PendingSweep>> = RefCell::new(BTreeMap::new());
+}
+
+/// The usual principal-to-subaccount encoding: length byte, principal bytes, zero padding.
+pub fn deposit_subaccount(user: &Principal) -> Subaccount {
+    let bytes = user.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+fn deposit_account_of(user: &Principal) -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(deposit_subaccount(user)),
+    }
+}
+
+pub(crate) fn take_depositors() -> BTreeSet<Principal> {
+    DEPOSITORS.with(|d| d.take())
+}
+
+pub(crate) fn restore_depositors(depositors: BTreeSet<Principal>) {
+    DEPOSITORS.with(|d| *d.borrow_mut() = depositors);
+}
+
+pub(crate) fn take_pending_sweeps() -> BTreeMap<Principal, PendingSweep> {
+    PENDING_SWEEPS.with(|p| p.take())
+}
+
+pub(crate) fn restore_pending_sweeps(pending: BTreeMap<Principal, PendingSweep>) {
+    PENDING_SWEEPS.with(|p| *p.borrow_mut() = pending);
+}
+
+/// Ledger account the caller can fund with a plain `icrc1_transfer` instead of
+/// an approval and `deposit_to_pool`. Deposits are credited when swept.
+#[ic_cdk::update]
+fn deposit_account() -> Result<Account, BridgeError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err(BridgeError::Unauthorized(
+            "Anonymous principal has no deposit account".to_string(),
+        ));
+    }
+    DEPOSITORS.with(|d| d.borrow_mut().insert(caller));
+    Ok(deposit_account_of(&caller))
+}
+
+// The next users after the cursor, wrapping around, so every depositor gets swept eventually.
+fn next_depositors() -> Vec<Principal> {
+    DEPOSITORS.with(|d| {
+        let depositors = d.borrow();
+        let cursor = SWEEP_CURSOR.with(|c| *c.borrow());
+        let users: Vec<Principal> = match cursor {
+            Some(after) => depositors
+                .range(after..)
+                .skip_while(|user| **user == after)
+                .chain(depositors.range(..=after))
+                .take(MAX_SWEEPS_PER_RUN)
+                .copied()
+                .collect(),
+            None => depositors.iter().take(MAX_SWEEPS_PER_RUN).copied().collect(),
+        };
+        SWEEP_CURSOR.with(|c| *c.borrow_mut() = users.last().copied());
+        users
+    })
+}
+
+pub async fn sweep_deposit_subaccounts() -> Result<(), BridgeError> {
+    if crate::check_not_paused().is_err() {
+        return Ok(());
+    }
+    let users = next_depositors();
+    if users.is_empty() {
+        return Ok(());
+    }
+    let ledger = read_config(|c| c.ledger_id);
+    let (fee,): (Nat,) = call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|err| BridgeError::call_failed("icrc1_fee", err))?;
+
+    let mut errors = Vec::new();
+    for user in users {
+        match sweep(ledger, user, fee.clone()).await {
+            // a user with a call in flight is not a failure of the sweep
+            Ok(()) | Err(BridgeError::RateLimited(_)) => {}
+            Err(e) => errors.push(format!("{}: {}", user, e)),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(BridgeError::Internal(errors.join("; ")))
+    }
+}
+
+async fn sweep(ledger: Principal, user: Principal, fee: Nat) -> Result<(), BridgeError> {
+    // skipped while the user has a call in flight, and swept on a later pass
+    let _guard = PrincipalGuard::new(user)?;
+
+    let sweep = match PENDING_SWEEPS.with(|p| p.borrow().get(&user).cloned()) {
+        Some(pending) => pending,
+        None => {
+            let account = deposit_account_of(&user);
+            let (balance,): (Nat,) = call(ledger, "icrc1_balance_of", (account,))
+                .await
+                .map_err(|err| BridgeError::call_failed("icrc1_balance_of", err))?;
+            if balance <= fee {
+                return Ok(());
+            }
+            let amount = balance - fee.clone();
+            let credited = match amount.0.to_u64_digits().as_slice() {
+                [] => 0,
+                [digit] => *digit,
+                _ => {
+                    return Err(BridgeError::InvalidAmount(format!(
+                        "deposit of {} does not fit in a u64 balance",
+                        amount
+                    )))
+                }
+            };
+            let args = TransferArg {
+                from_subaccount: account.subaccount,
+                to: Account {
+                    owner: ic_cdk::id(),
+                    subaccount: None,
+                },
+                fee: Some(fee),
+                created_at_time: Some(ic_cdk::api::time()),
+                memo: Some(Memo::from(b"sweep".to_vec())),
+                amount,
+            };
+            PendingSweep { args, credited }
+        }
+    };
+
+    PENDING_SWEEPS.with(|p| p.borrow_mut().insert(user, sweep.clone()));
+    let (result,): (Result<Nat, TransferError>,) = call(ledger, "icrc1_transfer", (sweep.args,))
+        .await
+        .map_err(|err| BridgeError::call_failed("icrc1_transfer", err))?;
+    PENDING_SWEEPS.with(|p| p.borrow_mut().remove(&user));
+    match result {
+        Ok(_) | Err(TransferError::Duplicate { .. }) => {}
+        Err(e) => return Err(BridgeError::LedgerTransferError(e)),
+    }
+
+    crate::credit_user(user, sweep.credited);
+    Ok(())
+}
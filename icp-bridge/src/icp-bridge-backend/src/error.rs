@@ -0,0 +1,67 @@
+use candid::CandidType;
+use ic_cdk::api::call::RejectionCode;
+use icrc_ledger_types::icrc1::transfer::TransferError;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
+use serde::Deserialize;
+use std::fmt;
+
+/// Error returned by the bridge endpoints. Variants carrying text add detail
+/// for humans; clients should only match on the variant.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum BridgeError {
+    Paused,
+    Unauthorized(String),
+    /// The caller already has an operation in flight, or the canister has too many.
+    RateLimited(String),
+    InsufficientBalance { available: u64, requested: u64 },
+    InvalidAmount(String),
+    InvalidAddress(String),
+    InvalidArgument(String),
+    InvalidConfig(String),
+    NotFound(String),
+    /// The ticket or lock is not in a state that allows the operation.
+    InvalidState(String),
+    /// The ledger rejected an `icrc2_transfer_from`.
+    LedgerError(TransferFromError),
+    /// The ledger rejected an `icrc1_transfer`.
+    LedgerTransferError(TransferError),
+    /// An inter-canister call was rejected before the callee could answer.
+    CallFailed(String),
+    /// The bridge public key has not been fetched from the management canister yet.
+    PublicKeyUnavailable,
+    SigningFailed(String),
+    SolanaRpcError(String),
+    Internal(String),
+}
+
+impl BridgeError {
+    pub fn call_failed(method: &str, (code, message): (RejectionCode, String)) -> Self {
+        BridgeError::CallFailed(format!("{} failed: {:?} {}", method, code, message))
+    }
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BridgeError::Paused => write!(f, "bridge is paused"),
+            BridgeError::InsufficientBalance { available, requested } => {
+                write!(f, "insufficient balance: {} available, {} requested", available, requested)
+            }
+            BridgeError::LedgerError(err) => write!(f, "ledger rejected the transfer: {:?}", err),
+            BridgeError::LedgerTransferError(err) => write!(f, "ledger rejected the transfer: {:?}", err),
+            BridgeError::PublicKeyUnavailable => write!(f, "bridge public key not fetched yet"),
+            BridgeError::Unauthorized(msg)
+            | BridgeError::RateLimited(msg)
+            | BridgeError::InvalidAmount(msg)
+            | BridgeError::InvalidAddress(msg)
+            | BridgeError::InvalidArgument(msg)
+            | BridgeError::InvalidConfig(msg)
+            | BridgeError::NotFound(msg)
+            | BridgeError::InvalidState(msg)
+            | BridgeError::CallFailed(msg)
+            | BridgeError::SigningFailed(msg)
+            | BridgeError::SolanaRpcError(msg)
+            | BridgeError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
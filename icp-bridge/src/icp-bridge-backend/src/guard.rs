@@ -0,0 +1,47 @@
+use crate::error::BridgeError;
+use candid::Principal;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+
+/// Upper bound on bridge operations that may be awaiting inter-canister calls at the same time.
+const MAX_CONCURRENT_OPERATIONS: usize = 100;
+
+thread_local! {
+    static IN_FLIGHT: RefCell<BTreeSet<Principal>> = RefCell::new(BTreeSet::new());
+}
+
+/// Marks an account as having a bridge operation in flight for as long as the guard lives.
+///
+/// The lock is released on drop, which covers every early return. If a
+/// callback traps after an `await`, ic-cdk drops the pending future from the
+/// `on_cleanup` callback, so the guard is released in that case as well.
+#[must_use]
+pub struct PrincipalGuard {
+    principal: Principal,
+}
+
+impl PrincipalGuard {
+    pub fn new(principal: Principal) -> Result<Self, BridgeError> {
+        IN_FLIGHT.with(|in_flight| {
+            let mut in_flight = in_flight.borrow_mut();
+            if in_flight.contains(&principal) {
+                return Err(BridgeError::RateLimited(
+                    "Another bridge operation for this account is in progress".to_string(),
+                ));
+            }
+            if in_flight.len() >= MAX_CONCURRENT_OPERATIONS {
+                return Err(BridgeError::RateLimited(
+                    "Too many concurrent bridge operations, try again later".to_string(),
+                ));
+            }
+            in_flight.insert(principal);
+            Ok(Self { principal })
+        })
+    }
+}
+
+impl Drop for PrincipalGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&self.principal));
+    }
+}
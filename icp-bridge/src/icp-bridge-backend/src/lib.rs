@@ -1,15 +1,36 @@
 ic_cdk::export_candid!();
-use candid::{CandidType, Principal, Nat};
-use ic_cdk::api::call::call;
+use base64::Engine;
+use candid::{CandidType, Principal};
 use ic_cdk::caller;
 use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use icrc_ledger_types::icrc1::transfer::Memo;
-use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
 use serde::{Deserialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::cell::RefCell;
-use std::fmt::format;
+mod batching;
+mod config;
+mod deposits;
+mod error;
+mod guard;
+mod reconcile;
+mod roles;
+mod scheduler;
 mod threshold_schnorr;
+mod tickets;
+mod transfers;
+mod merkle;
+mod solana_pda;
+mod solana_rpc;
+mod wrapped;
+
+use config::{BridgeArg, Config, UpgradeArgs};
+use error::BridgeError;
+use guard::PrincipalGuard;
+use roles::{is_admin, is_pauser, Role};
+use tickets::{Ticket, TicketStatus};
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(CandidType, Deserialize, Default, Clone)]
 struct UserBalance {
@@ -19,23 +40,167 @@ struct UserBalance {
 thread_local! {
     static USER_BALANCES: RefCell<HashMap<Principal, UserBalance>> = RefCell::new(HashMap::new());
     static POOL_BALANCE: RefCell<u64> = RefCell::new(0);
+    static PAUSED: RefCell<bool> = RefCell::new(false);
 }
 
-fn convert_option_memo(option: Option<Vec<u8>>) -> Option<Memo> {
-    option.map(|vec| Memo::from(vec)) // 使用 Memo::from 来转换 Vec<u8> 为 Memo
+/// Everything that has to survive an upgrade, written to stable memory in `pre_upgrade`.
+#[derive(CandidType, Deserialize)]
+struct StableState {
+    user_balances: HashMap<Principal, UserBalance>,
+    pool_balance: u64,
+    config: Config,
+    user_solana_addresses: HashMap<Principal, String>,
+    bridge_public_key: Option<threshold_schnorr::CachedPublicKey>,
+    roles: Option<BTreeMap<Principal, BTreeSet<Role>>>,
+    paused: Option<bool>,
+    key_rotation: Option<threshold_schnorr::KeyRotationState>,
+    tickets: Option<tickets::TicketState>,
+    batches: Option<batching::BatchState>,
+    scheduler: Option<scheduler::SchedulerState>,
+    depositors: Option<BTreeSet<Principal>>,
+    reconciliation: Option<reconcile::ReconciliationState>,
+    wrapped: Option<wrapped::WrappedState>,
+    pending_transfers: Option<BTreeMap<Principal, transfers::PendingTransfer>>,
+    pending_sweeps: Option<BTreeMap<Principal, deposits::PendingSweep>>,
+}
+
+#[ic_cdk::init]
+fn init(arg: BridgeArg) {
+    match arg {
+        BridgeArg::Init(args) => {
+            let config = Config::from_init_args(args).unwrap_or_else(|e| ic_cdk::trap(&e));
+            config::set_config(config);
+        }
+        BridgeArg::Upgrade(_) => ic_cdk::trap("expected Init arguments when installing the canister"),
+    }
+    threshold_schnorr::schedule_bridge_public_key_fetch();
+    scheduler::start();
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let state = StableState {
+        user_balances: USER_BALANCES.with(|b| b.take()),
+        pool_balance: POOL_BALANCE.with(|b| *b.borrow()),
+        config: config::read_config(|c| c.clone()),
+        user_solana_addresses: threshold_schnorr::take_user_solana_addresses(),
+        bridge_public_key: threshold_schnorr::cached_bridge_public_key(),
+        roles: Some(roles::take_roles()),
+        paused: Some(PAUSED.with(|p| *p.borrow())),
+        key_rotation: Some(threshold_schnorr::key_rotation_state()),
+        tickets: Some(tickets::take_ticket_state()),
+        batches: Some(batching::take_batch_state()),
+        scheduler: Some(scheduler::take_scheduler_state()),
+        depositors: Some(deposits::take_depositors()),
+        reconciliation: Some(reconcile::take_reconciliation_state()),
+        wrapped: Some(wrapped::take_wrapped_state()),
+        pending_transfers: Some(transfers::take_pending_transfers()),
+        pending_sweeps: Some(deposits::take_pending_sweeps()),
+    };
+    ic_cdk::storage::stable_save((state,)).expect("failed to save state to stable memory");
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade(arg: Option<BridgeArg>) {
+    // Versions before the config existed kept nothing in stable memory; those
+    // have to be upgraded with full Init arguments.
+    if ic_cdk::api::stable::stable_size() == 0 {
+        match arg {
+            Some(BridgeArg::Init(args)) => {
+                let config = Config::from_init_args(args).unwrap_or_else(|e| ic_cdk::trap(&e));
+                config::set_config(config);
+                threshold_schnorr::schedule_bridge_public_key_fetch();
+                scheduler::start();
+                return;
+            }
+            _ => ic_cdk::trap("no saved state found; upgrade with Init arguments"),
+        }
+    }
+
+    let (state,): (StableState,) =
+        ic_cdk::storage::stable_restore().expect("failed to restore state from stable memory");
+
+    let mut config = state.config;
+    match arg {
+        Some(BridgeArg::Upgrade(Some(args))) => {
+            config.apply_upgrade_args(args).unwrap_or_else(|e| ic_cdk::trap(&e))
+        }
+        Some(BridgeArg::Upgrade(None)) | None => {}
+        Some(BridgeArg::Init(_)) => ic_cdk::trap("expected Upgrade arguments when upgrading the canister"),
+    }
+    config::set_config(config);
+
+    USER_BALANCES.with(|b| *b.borrow_mut() = state.user_balances);
+    POOL_BALANCE.with(|b| *b.borrow_mut() = state.pool_balance);
+    threshold_schnorr::restore_user_solana_addresses(state.user_solana_addresses);
+    threshold_schnorr::restore_key_rotation_state(state.key_rotation.unwrap_or_default());
+    threshold_schnorr::restore_bridge_public_key(state.bridge_public_key);
+    threshold_schnorr::schedule_bridge_public_key_fetch();
+    roles::restore_roles(state.roles.unwrap_or_default());
+    PAUSED.with(|p| *p.borrow_mut() = state.paused.unwrap_or(false));
+    tickets::restore_ticket_state(state.tickets.unwrap_or_default());
+    batching::restore_batch_state(state.batches.unwrap_or_default());
+    deposits::restore_depositors(state.depositors.unwrap_or_default());
+    reconcile::restore_reconciliation_state(state.reconciliation.unwrap_or_default());
+    wrapped::restore_wrapped_state(state.wrapped.unwrap_or_default());
+    transfers::restore_pending_transfers(state.pending_transfers.unwrap_or_default());
+    deposits::restore_pending_sweeps(state.pending_sweeps.unwrap_or_default());
+    scheduler::restore_scheduler_state(state.scheduler.unwrap_or_default());
+    scheduler::start();
+}
+
+fn check_not_paused() -> Result<(), BridgeError> {
+    if PAUSED.with(|p| *p.borrow()) {
+        return Err(BridgeError::Paused);
+    }
+    Ok(())
+}
+
+fn set_paused(paused: bool) {
+    PAUSED.with(|p| *p.borrow_mut() = paused);
+}
+
+#[ic_cdk::update(guard = "is_pauser")]
+fn pause() {
+    set_paused(true);
 }
 
-fn concat_u64_and_string(value: u64, text: String) -> String {
-    format!("{}_{}", value, text)
+// Resuming is deliberately reserved to admins: a pauser key is meant to be
+// usable in an emergency, not to be enough to restart the bridge.
+#[ic_cdk::update(guard = "is_admin")]
+fn unpause() {
+    set_paused(false);
+}
+
+#[ic_cdk::query]
+fn is_paused() -> bool {
+    PAUSED.with(|p| *p.borrow())
+}
+
+/// Changes the configuration without an upgrade, with the same validation as `post_upgrade`.
+#[ic_cdk::update(guard = "is_admin")]
+fn update_config(args: UpgradeArgs) -> Result<Config, BridgeError> {
+    let mut config = config::read_config(|c| c.clone());
+    config.apply_upgrade_args(args).map_err(BridgeError::InvalidConfig)?;
+    config::set_config(config.clone());
+    threshold_schnorr::refresh_after_config_change();
+    Ok(config)
+}
+
+fn convert_option_memo(option: Option<Vec<u8>>) -> Option<Memo> {
+    option.map(Memo::from) // 使用 Memo::from 来转换 Vec<u8> 为 Memo
 }
 
 #[ic_cdk::update]
-async fn deposit_to_pool(from_subaccount: Option<Subaccount>, amount: u64, spender_subaccount: Option<Subaccount>, memo: Option<Vec<u8>>) -> Result<u64, String> {
-    let icrc2_canister_id = Principal::from_text("avqkn-guaaa-aaaaa-qaaea-cai").unwrap();
+async fn deposit_to_pool(from_subaccount: Option<Subaccount>, amount: u64, spender_subaccount: Option<Subaccount>, memo: Option<Vec<u8>>) -> Result<u64, BridgeError> {
+    let icrc2_canister_id = config::read_config(|c| c.ledger_id);
+    check_not_paused()?;
+    let caller = ic_cdk::caller();
+    let _guard = PrincipalGuard::new(caller)?;
 
     // 设置 `from` 和 `to` 账户
     let from_account = Account {
-        owner: ic_cdk::caller(),
+        owner: caller,
         subaccount: from_subaccount,
     };
     let pool_account = Account {
@@ -43,6 +208,8 @@ async fn deposit_to_pool(from_subaccount: Option<Subaccount>, amount: u64, spend
         subaccount: None,
     };
 
+    transfers::settle_pending(caller).await?;
+
     // 构建 `TransferFromArgs`
     let transfer_args = TransferFromArgs {
         spender_subaccount,
@@ -51,49 +218,42 @@ async fn deposit_to_pool(from_subaccount: Option<Subaccount>, amount: u64, spend
         amount: amount.into(),
         fee: None,
         memo: convert_option_memo(memo),
-        created_at_time: None,
+        created_at_time: Some(ic_cdk::api::time()),
     };
 
     // 调用 `icrc2_transfer_from` 方法从用户账户转账到池子账户
-    let (result, ):(Result<Nat, TransferFromError>,) = call(icrc2_canister_id, "icrc2_transfer_from", (transfer_args,))
-        .await
-        .map_err(|err| format!("Transfer failed: {:?}", err))?;
-
-    print!("Transfer result: {:?}", result);
-    // 更新用户余额和池子余额
-    let caller = ic_cdk::caller();
-    USER_BALANCES.with(|balances| {
-        let mut balances = balances.borrow_mut();
-        let user_balance = balances.entry(caller).or_insert_with(UserBalance::default);
-        user_balance.amount += amount;
-    });
-
-    POOL_BALANCE.with(|balance| *balance.borrow_mut() += amount);
-
-    // todo, for now just u64 size
-    Ok(result.unwrap().0.to_u64_digits()[0])
+    // only credits what the ledger actually moved
+    transfers::transfer_from(
+        caller,
+        transfers::PendingTransfer {
+            ledger: icrc2_canister_id,
+            args: transfer_args,
+            settlement: transfers::Settlement::Credit { amount },
+        },
+    )
+    .await
 }
 
 
+/// Deducts `amount` and queues a ticket paying it to `solana_address`, a token
+/// account of the configured `solana_token_mint`. The ticket is signed with
+/// the next batch, see `get_ticket_proof`.
 #[ic_cdk::update]
-async fn bridge_to_solana(amount: u64, solana_address: String) -> Result<String, String> {
+async fn bridge_to_solana(amount: u64, solana_address: String) -> Result<Ticket, BridgeError> {
+    check_not_paused()?;
     let caller = caller();
-    
-    // 检查用户余额
-    let user_balance = USER_BALANCES.with(|balances| {
-        let balances = balances.borrow();
-        balances.get(&caller).cloned().unwrap_or_default()
-    });
-
-    // 确保用户有足够的余额
-    if user_balance.amount < amount {
-        return Err("Insufficient balance".to_string());
+    let _guard = PrincipalGuard::new(caller)?;
+    if amount == 0 {
+        return Err(BridgeError::InvalidAmount("Amount must be greater than 0".to_string()));
     }
-
-    // the verification work for solana_address, leaves to the frontend
-    // if !is_valid_solana_address(address) {
-    //     return Err("Invalid Solana Address".to_string());
-    // }
+    let mint = config::read_config(|c| c.solana_token_mint.clone())
+        .ok_or_else(|| BridgeError::InvalidConfig("solana_token_mint is not configured".to_string()))?;
+    // the batch leaf commits to the raw 32 bytes of the address
+    solana_pda::decode_pubkey(&solana_address).map_err(BridgeError::InvalidAddress)?;
+    check_user_balance(caller, amount)?;
+    check_token_account(&solana_address, &mint).await?;
+    // the balance may have changed while the outcall was in flight
+    check_user_balance(caller, amount)?;
 
     // 更新用户余额和池子余额
     USER_BALANCES.with(|balances| {
@@ -107,14 +267,146 @@ async fn bridge_to_solana(amount: u64, solana_address: String) -> Result<String,
         *balance -= amount; // 从池子余额中扣除
     });
 
-    let signature = threshold_schnorr::schnorr_sign(concat_u64_and_string(amount, solana_address))
+    Ok(tickets::queue_ticket(
+        tickets::next_ticket_id(),
+        caller,
+        amount,
+        solana_address,
+        None,
+        None,
+    ))
+}
+
+// 确保用户有足够的余额
+fn check_user_balance(user: Principal, amount: u64) -> Result<(), BridgeError> {
+    let available = USER_BALANCES.with(|balances| balances.borrow().get(&user).map_or(0, |b| b.amount));
+    if available < amount {
+        return Err(BridgeError::InsufficientBalance {
+            available,
+            requested: amount,
+        });
+    }
+    Ok(())
+}
+
+/// Checks through the RPC quorum that `address` is a token account of `mint`,
+/// the only kind of recipient `withdraw_batch` pays.
+async fn check_token_account(address: &str, mint: &str) -> Result<(), BridgeError> {
+    let rpc = config::read_config(|c| c.solana_rpc_client());
+    let account = rpc
+        .get_account_info(address)
+        .await
+        .map_err(|e| BridgeError::SolanaRpcError(format!("Could not read {} on Solana: {}", address, e)))?
+        .filter(|account| account.owner == solana_pda::TOKEN_PROGRAM_ID)
+        .ok_or_else(|| BridgeError::InvalidAddress(format!("{} is not an SPL token account", address)))?;
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(&account.data.0)
+        .map_err(|e| BridgeError::SolanaRpcError(format!("Invalid account data: {}", e)))?;
+    // an SPL token account starts with its mint
+    let mint = solana_pda::decode_pubkey(mint).map_err(BridgeError::InvalidConfig)?;
+    if data.len() != TOKEN_ACCOUNT_LEN || data[..32] != mint {
+        return Err(BridgeError::InvalidAddress(format!(
+            "{} is not a token account of the bridged mint",
+            address
+        )));
+    }
+    Ok(())
+}
+
+const TOKEN_ACCOUNT_LEN: usize = 165;
+
+/// Gives the amount of an expired, never redeemed ticket back to its owner's
+/// bridge balance, after checking on Solana that its nonce was not consumed.
+#[ic_cdk::update]
+async fn refund_ticket(id: u64) -> Result<Ticket, BridgeError> {
+    let caller = caller();
+    let _guard = PrincipalGuard::new(caller)?;
+
+    let ticket = tickets::get(id).ok_or_else(|| BridgeError::NotFound(format!("Ticket {} not found", id)))?;
+    if ticket.owner != caller {
+        return Err(BridgeError::Unauthorized(
+            "Only the ticket owner can request a refund".to_string(),
+        ));
+    }
+    if !matches!(ticket.status, TicketStatus::Queued | TicketStatus::Signed) {
+        return Err(BridgeError::InvalidState(format!("Ticket {} is already {:?}", id, ticket.status)));
+    }
+    if !ticket.is_refundable(tickets::now_seconds()) {
+        return Err(BridgeError::InvalidState(format!(
+            "Ticket {} can be refunded {} seconds after its expiry at {}",
+            id,
+            tickets::REFUND_GRACE_SECONDS,
+            ticket.expiry
+        )));
+    }
+
+    match settle_expired_ticket(id).await? {
+        TicketStatus::Redeemed => Err(BridgeError::InvalidState(format!("Ticket {} was redeemed on Solana", id))),
+        _ => Ok(tickets::get(id).expect("ticket exists")),
+    }
+}
+
+/// Whether the Solana program created the receipt for ticket `id`, i.e. consumed its nonce.
+/// Anyone can send lamports to the receipt address, so only an account the
+/// program owns and wrote as the receipt of this nonce counts.
+async fn receipt_exists(id: u64) -> Result<bool, BridgeError> {
+    let (program_id, rpc) = config::read_config(|c| (c.solana_program_id.clone(), c.solana_rpc_client()));
+    let receipt = solana_pda::receipt_address(&program_id, id).map_err(BridgeError::InvalidConfig)?;
+    let receipt_account = rpc
+        .get_account_info(&receipt)
         .await
-        .map_err(|e| format!("Sign Failed: {}", e))?;
+        .map_err(|e| BridgeError::SolanaRpcError(format!("Could not check the ticket on Solana: {}", e)))?;
+    let Some(account) = receipt_account.filter(|account| account.owner == program_id) else {
+        return Ok(false);
+    };
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(&account.data.0)
+        .map_err(|e| BridgeError::SolanaRpcError(format!("Invalid receipt data: {}", e)))?;
+    Ok(is_receipt_of(&data, id))
+}
 
-    return Ok(signature.signature_hex);
+// Borsh layout after Anchor's 8-byte discriminator starts with the nonce (u64).
+fn is_receipt_of(data: &[u8], nonce: u64) -> bool {
+    let discriminator = Sha256::digest(b"account:Receipt");
+    data.len() >= 16 && data[..8] == discriminator[..8] && data[8..16] == nonce.to_le_bytes()
 }
 
+/// Marks a refundable ticket redeemed if its nonce was consumed on Solana and
+/// refunds it otherwise. Shared by `refund_ticket` and the `ExpireTickets` task.
+async fn settle_expired_ticket(id: u64) -> Result<TicketStatus, BridgeError> {
+    let redeemed = receipt_exists(id).await?;
 
+    // another call may have settled the ticket while the outcall was in flight
+    let ticket = tickets::get(id)
+        .filter(|ticket| ticket.is_refundable(tickets::now_seconds()))
+        .ok_or_else(|| BridgeError::InvalidState(format!("Ticket {} was settled concurrently", id)))?;
+    if redeemed {
+        tickets::set_status(id, TicketStatus::Redeemed);
+        return Ok(TicketStatus::Redeemed);
+    }
+    tickets::set_status(id, TicketStatus::Refunded);
+    match &ticket.wrapped_mint {
+        None => credit_user(ticket.owner, ticket.amount),
+        // the burnt wrapped tokens are minted back; the ticket is marked refunded
+        // first so nothing else settles it while the ledger call is in flight
+        Some(mint) => {
+            let memo = format!("refund {}", id).into_bytes();
+            if let Err(e) = wrapped::mint_wrapped(mint, ticket.owner, ticket.amount, memo).await {
+                tickets::set_status(id, ticket.status);
+                return Err(e);
+            }
+        }
+    }
+    Ok(TicketStatus::Refunded)
+}
+
+/// Adds tokens that reached the pool account to `user`'s bridge balance.
+fn credit_user(user: Principal, amount: u64) {
+    USER_BALANCES.with(|balances| {
+        balances.borrow_mut().entry(user).or_insert_with(UserBalance::default).amount += amount;
+    });
+    POOL_BALANCE.with(|pool_balance| *pool_balance.borrow_mut() += amount);
+}
 
 #[ic_cdk::query]
 fn get_user_balance(user: Principal) -> u64 {
@@ -127,6 +419,17 @@ fn get_user_balance(user: Principal) -> u64 {
     })
 }
 
+/// Sum of all user balances, `None` if it overflows a u64, and the `POOL_BALANCE` counter.
+fn balance_totals() -> (Option<u64>, u64) {
+    let users = USER_BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .values()
+            .try_fold(0u64, |total, balance| total.checked_add(balance.amount))
+    });
+    (users, POOL_BALANCE.with(|balance| *balance.borrow()))
+}
+
 #[ic_cdk::query]
 fn get_pool_balance() -> u64 {
     POOL_BALANCE.with(|balance| *balance.borrow())
@@ -140,3 +443,25 @@ fn get_canister_id() -> String {
     // 将 Canister ID 转换为字符串并返回
     canister_id.to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receipt_data(discriminator: &[u8], nonce: u64) -> Vec<u8> {
+        let mut data = discriminator[..8].to_vec();
+        data.extend_from_slice(&nonce.to_le_bytes());
+        data.extend_from_slice(&[0u8; 8 + 32 + 8 + 1]);
+        data
+    }
+
+    #[test]
+    fn receipt_must_match_discriminator_and_nonce() {
+        let receipt = Sha256::digest(b"account:Receipt");
+        assert!(is_receipt_of(&receipt_data(&receipt, 7), 7));
+        assert!(!is_receipt_of(&receipt_data(&receipt, 8), 7));
+        assert!(!is_receipt_of(&receipt_data(&Sha256::digest(b"account:Vault"), 7), 7));
+        // a pre-funded, system-owned address has no data at all
+        assert!(!is_receipt_of(&[], 7));
+    }
+}
@@ -0,0 +1,131 @@
+use sha2::{Digest, Sha256};
+
+// Must match `utils::merkle` in the Solana program byte for byte.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Hashed in place of the mint for tickets paid in native SOL; `withdraw_sol`
+/// only accepts leaves with this marker.
+pub const NATIVE_SOL: [u8; 32] = [0; 32];
+
+/// Hash of one ticket; committing to the mint keeps it from being paid out of
+/// a vault of another token.
+pub fn withdrawal_leaf(nonce: u64, amount: u64, mint: &[u8; 32], recipient: &[u8; 32], expiry: i64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(nonce.to_le_bytes());
+    hasher.update(amount.to_le_bytes());
+    hasher.update(mint);
+    hasher.update(recipient);
+    hasher.update(expiry.to_le_bytes());
+    hasher.finalize().into()
+}
+
+/// Pairs are hashed in sorted order, so proofs carry no left/right flags.
+fn hash_pair(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(first);
+    hasher.update(second);
+    hasher.finalize().into()
+}
+
+/// All levels of the tree, leaves first. A node without a sibling is carried
+/// up to the next level unchanged.
+pub struct MerkleTree {
+    levels: Vec<Vec<[u8; 32]>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<[u8; 32]>) -> Self {
+        assert!(!leaves.is_empty(), "a Merkle tree needs at least one leaf");
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [a, b] => hash_pair(a, b),
+                    [a] => *a,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        Self { levels }
+    }
+
+    pub fn root(&self) -> [u8; 32] {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// Siblings from the leaf at `index` up to the root.
+    pub fn proof(&self, mut index: usize) -> Vec<[u8; 32]> {
+        let mut proof = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        proof
+    }
+}
+
+/// The message the bridge signs for a batch; `withdraw_batch` rebuilds it from the root.
+pub fn batch_message(root: &[u8; 32]) -> String {
+    format!("root_{}", hex::encode(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: u64) -> Vec<[u8; 32]> {
+        (0..count)
+            .map(|nonce| withdrawal_leaf(nonce, 1_000 * (nonce + 1), &[1; 32], &[2; 32], 1_700_000_000))
+            .collect()
+    }
+
+    // Same fold as `verify_merkle_proof` in the Solana program.
+    fn fold_proof(leaf: [u8; 32], proof: &[[u8; 32]]) -> [u8; 32] {
+        proof.iter().fold(leaf, |node, sibling| hash_pair(&node, sibling))
+    }
+
+    #[test]
+    fn every_proof_folds_to_the_root() {
+        for count in [1, 2, 3, 5] {
+            let leaves = leaves(count);
+            let tree = MerkleTree::new(leaves.clone());
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(index);
+                assert_eq!(fold_proof(*leaf, &proof), tree.root(), "leaf {} of {}", index, count);
+                assert_ne!(fold_proof([0; 32], &proof), tree.root());
+            }
+        }
+    }
+
+    #[test]
+    fn single_leaf_is_its_own_root() {
+        let leaves = leaves(1);
+        let tree = MerkleTree::new(leaves.clone());
+        assert_eq!(tree.root(), leaves[0]);
+        assert!(tree.proof(0).is_empty());
+    }
+
+    // The Solana program pins the same vector in `utils::merkle`.
+    #[test]
+    fn matches_the_vector_shared_with_solana() {
+        let leaves = leaves(3);
+        assert_eq!(
+            hex::encode(leaves[0]),
+            "b2c867a8c3c5bfcdb5f2449974e697358392be9a2918ae3b749a3b9d61d89993"
+        );
+        assert_eq!(
+            batch_message(&MerkleTree::new(leaves).root()),
+            "root_7f2d5e58e4b5b058dab367be3bc08ef4d8d01aa62f83ec97424296a07a412ad3"
+        );
+    }
+}
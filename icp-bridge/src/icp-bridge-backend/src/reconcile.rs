@@ -0,0 +1,158 @@
+use crate::config::read_config;
+use crate::error::BridgeError;
+use crate::roles::{is_admin, is_operator};
+use crate::tickets::{self, TicketStatus};
+use candid::{CandidType, Nat};
+use ic_cdk::api::call::call;
+use icrc_ledger_types::icrc1::account::Account;
+use serde::Deserialize;
+use std::cell::RefCell;
+
+const MAX_DISCREPANCIES: usize = 100;
+
+/// Ledger balance of the pool account against what the bridge owes.
+///
+/// Ledger fees do not appear here: `icrc2_transfer_from` charges them to the
+/// depositor and sweeps pay them out of the deposit subaccount, so the pool
+/// receives exactly the credited amounts.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReconciliationReport {
+    pub timestamp: u64,
+    pub ledger_balance: u64,
+    /// Sum of all user bridge balances.
+    pub user_balances: u64,
+    /// The `POOL_BALANCE` counter, which should equal `user_balances`.
+    pub pool_counter: u64,
+    /// Queued and signed tickets, which can still be refunded.
+    pub pending_tickets: u64,
+    /// Redeemed tickets, pruned ones included; their tokens stay locked in the pool.
+    pub bridged_out: u64,
+    pub expected: u64,
+    pub deficit: u64,
+    pub surplus: u64,
+    pub issues: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct ReconciliationState {
+    pub last: Option<ReconciliationReport>,
+    /// Most recent reports with issues, oldest first.
+    pub discrepancies: Vec<ReconciliationReport>,
+    pub auto_pause_on_deficit: bool,
+}
+
+thread_local! {
+    static RECONCILIATION: RefCell<ReconciliationState> = RefCell::new(ReconciliationState::default());
+}
+
+pub(crate) fn take_reconciliation_state() -> ReconciliationState {
+    RECONCILIATION.with(|r| r.take())
+}
+
+pub(crate) fn restore_reconciliation_state(state: ReconciliationState) {
+    RECONCILIATION.with(|r| *r.borrow_mut() = state);
+}
+
+fn sum_tickets(statuses: &[TicketStatus]) -> Result<u64, BridgeError> {
+    statuses
+        .iter()
+        .flat_map(|status| tickets::with_status(*status))
+        // wrapped tokens were burnt, they never went through the pool
+        .filter(|ticket| ticket.wrapped_mint.is_none())
+        .try_fold(0u64, |total, ticket| total.checked_add(ticket.amount))
+        .ok_or_else(|| BridgeError::Internal(format!("total of {:?} tickets overflows a u64", statuses)))
+}
+
+/// Compares the ledger with the internal books, records any discrepancy and
+/// pauses the bridge on a deficit if `auto_pause_on_deficit` is set.
+pub async fn run_reconciliation() -> Result<ReconciliationReport, BridgeError> {
+    let ledger = read_config(|c| c.ledger_id);
+    let pool_account = Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    };
+    let (balance,): (Nat,) = call(ledger, "icrc1_balance_of", (pool_account,))
+        .await
+        .map_err(|err| BridgeError::call_failed("icrc1_balance_of", err))?;
+    let ledger_balance = match balance.0.to_u64_digits().as_slice() {
+        [] => 0,
+        [digit] => *digit,
+        _ => return Err(BridgeError::Internal(format!("pool balance {} does not fit in a u64", balance))),
+    };
+
+    // Read after the await: every flow updates the ledger before the books, so
+    // in-flight operations can only show up as a temporary surplus.
+    let (user_balances, pool_counter) = crate::balance_totals();
+    let user_balances =
+        user_balances.ok_or_else(|| BridgeError::Internal("sum of user balances overflows a u64".to_string()))?;
+    let pending_tickets = sum_tickets(&[TicketStatus::Queued, TicketStatus::Signed])?;
+    let bridged_out = sum_tickets(&[TicketStatus::Redeemed])?
+        .checked_add(tickets::pruned_bridged_out())
+        .ok_or_else(|| BridgeError::Internal("redeemed ticket total overflows a u64".to_string()))?;
+    let expected = user_balances
+        .checked_add(pending_tickets)
+        .and_then(|total| total.checked_add(bridged_out))
+        .ok_or_else(|| {
+            BridgeError::Internal(format!(
+                "expected balance overflows a u64: {} in balances, {} pending, {} bridged out",
+                user_balances, pending_tickets, bridged_out
+            ))
+        })?;
+
+    let mut issues = Vec::new();
+    if pool_counter != user_balances {
+        issues.push(format!(
+            "pool counter {} differs from the sum of user balances {}",
+            pool_counter, user_balances
+        ));
+    }
+    let deficit = expected.saturating_sub(ledger_balance);
+    if deficit > 0 {
+        issues.push(format!("ledger balance {} is {} short of {}", ledger_balance, deficit, expected));
+    }
+
+    let report = ReconciliationReport {
+        timestamp: ic_cdk::api::time(),
+        ledger_balance,
+        user_balances,
+        pool_counter,
+        pending_tickets,
+        bridged_out,
+        expected,
+        deficit,
+        surplus: ledger_balance.saturating_sub(expected),
+        issues,
+    };
+
+    let auto_pause = RECONCILIATION.with(|r| {
+        let mut state = r.borrow_mut();
+        state.last = Some(report.clone());
+        if !report.issues.is_empty() {
+            state.discrepancies.push(report.clone());
+            if state.discrepancies.len() > MAX_DISCREPANCIES {
+                state.discrepancies.remove(0);
+            }
+        }
+        state.auto_pause_on_deficit
+    });
+    if deficit > 0 && auto_pause {
+        ic_cdk::println!("pausing the bridge: {}", report.issues.join("; "));
+        crate::set_paused(true);
+    }
+    Ok(report)
+}
+
+#[ic_cdk::update(guard = "is_operator")]
+async fn reconcile() -> Result<ReconciliationReport, BridgeError> {
+    run_reconciliation().await
+}
+
+#[ic_cdk::query]
+fn get_reconciliation_state() -> ReconciliationState {
+    RECONCILIATION.with(|r| r.borrow().clone())
+}
+
+#[ic_cdk::update(guard = "is_admin")]
+fn set_auto_pause_on_deficit(enabled: bool) {
+    RECONCILIATION.with(|r| r.borrow_mut().auto_pause_on_deficit = enabled);
+}
@@ -0,0 +1,116 @@
+use crate::error::BridgeError;
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Roles that can be granted to principals. Controllers of the canister
+/// implicitly hold every role, and admins hold every role except granting admin.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Admin,
+    Operator,
+    Pauser,
+}
+
+thread_local! {
+    static ROLES: RefCell<BTreeMap<Principal, BTreeSet<Role>>> = RefCell::new(BTreeMap::new());
+}
+
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    if ic_cdk::api::is_controller(principal) {
+        return true;
+    }
+    ROLES.with(|roles| {
+        roles
+            .borrow()
+            .get(principal)
+            .map(|granted| granted.contains(&role) || granted.contains(&Role::Admin))
+            .unwrap_or(false)
+    })
+}
+
+fn require_role(role: Role) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if has_role(&caller, role) {
+        Ok(())
+    } else {
+        Err(format!("{} does not have the {:?} role", caller, role))
+    }
+}
+
+pub fn is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Only controllers can call this method".to_string())
+    }
+}
+
+pub fn is_admin() -> Result<(), String> {
+    require_role(Role::Admin)
+}
+
+pub fn is_operator() -> Result<(), String> {
+    require_role(Role::Operator)
+}
+
+pub fn is_pauser() -> Result<(), String> {
+    require_role(Role::Pauser)
+}
+
+pub(crate) fn take_roles() -> BTreeMap<Principal, BTreeSet<Role>> {
+    ROLES.with(|roles| roles.take())
+}
+
+pub(crate) fn restore_roles(restored: BTreeMap<Principal, BTreeSet<Role>>) {
+    ROLES.with(|roles| *roles.borrow_mut() = restored);
+}
+
+// Only controllers may hand out or take away Admin, so an admin cannot
+// entrench itself or lock out other admins.
+fn check_can_manage(role: Role) -> Result<(), BridgeError> {
+    if role == Role::Admin {
+        is_controller().map_err(BridgeError::Unauthorized)
+    } else {
+        Ok(())
+    }
+}
+
+#[ic_cdk::update(guard = "is_admin")]
+fn grant_role(principal: Principal, role: Role) -> Result<(), BridgeError> {
+    check_can_manage(role)?;
+    if principal == Principal::anonymous() {
+        return Err(BridgeError::InvalidArgument(
+            "Cannot grant roles to the anonymous principal".to_string(),
+        ));
+    }
+    ROLES.with(|roles| roles.borrow_mut().entry(principal).or_default().insert(role));
+    Ok(())
+}
+
+#[ic_cdk::update(guard = "is_admin")]
+fn revoke_role(principal: Principal, role: Role) -> Result<(), BridgeError> {
+    check_can_manage(role)?;
+    ROLES.with(|roles| {
+        let mut roles = roles.borrow_mut();
+        if let Some(granted) = roles.get_mut(&principal) {
+            granted.remove(&role);
+            if granted.is_empty() {
+                roles.remove(&principal);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_roles() -> Vec<(Principal, Vec<Role>)> {
+    ROLES.with(|roles| {
+        roles
+            .borrow()
+            .iter()
+            .map(|(principal, granted)| (*principal, granted.iter().copied().collect()))
+            .collect()
+    })
+}
@@ -0,0 +1,280 @@
+use crate::error::BridgeError;
+use crate::roles::is_admin;
+use crate::tickets::{self, TicketStatus};
+use crate::{batching, deposits, reconcile};
+use candid::CandidType;
+use ic_cdk_timers::TimerId;
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+const MIN_INTERVAL_SECONDS: u64 = 5;
+const MAX_INTERVAL_SECONDS: u64 = 24 * 60 * 60;
+/// Failing tasks back off exponentially up to this delay.
+const MAX_BACKOFF_SECONDS: u64 = 60 * 60;
+/// Each ticket costs one Solana RPC outcall per provider.
+const MAX_TICKETS_PER_RUN: usize = 10;
+
+/// Background jobs run by the canister on timers.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Task {
+    /// Signs queued tickets in Merkle batches, retrying after signing failures.
+    SignBatches,
+    /// Marks signed tickets whose nonce was consumed on Solana as redeemed.
+    PollConfirmations,
+    /// Moves funds sent to users' deposit subaccounts into the pool.
+    SweepDeposits,
+    /// Settles tickets past their expiry: redeemed, or refunded to the owner.
+    /// Also prunes tickets and batches settled long ago.
+    ExpireTickets,
+    /// Compares the pool's ledger balance with the bridge's books.
+    Reconcile,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TaskStatus {
+    pub task: Task,
+    pub interval_seconds: u64,
+    pub last_started_at: Option<u64>,
+    pub last_succeeded_at: Option<u64>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    pub next_run_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct SchedulerState {
+    pub tasks: BTreeMap<Task, TaskStatus>,
+    /// Id of the first ticket the next `ExpireTickets` run looks at.
+    pub expire_cursor: Option<u64>,
+}
+
+thread_local! {
+    static SCHEDULER: RefCell<SchedulerState> = RefCell::new(SchedulerState::default());
+    // not persisted: timers are gone after an upgrade and `start` re-arms them
+    static TIMERS: RefCell<BTreeMap<Task, TimerId>> = RefCell::new(BTreeMap::new());
+    static RUNNING: RefCell<BTreeSet<Task>> = RefCell::new(BTreeSet::new());
+    static POLL_CURSOR: Cell<u64> = Cell::new(0);
+}
+
+impl Task {
+    const ALL: [Task; 5] = [
+        Task::SignBatches,
+        Task::PollConfirmations,
+        Task::SweepDeposits,
+        Task::ExpireTickets,
+        Task::Reconcile,
+    ];
+
+    fn default_interval_seconds(&self) -> u64 {
+        match self {
+            Task::SignBatches => 30,
+            Task::PollConfirmations => 5 * 60,
+            Task::SweepDeposits => 2 * 60,
+            Task::ExpireTickets => 10 * 60,
+            Task::Reconcile => 15 * 60,
+        }
+    }
+
+    async fn run(self) -> Result<(), String> {
+        match self {
+            Task::SignBatches => batching::sign_queued_tickets()
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Task::PollConfirmations => poll_confirmations().await,
+            Task::SweepDeposits => deposits::sweep_deposit_subaccounts()
+                .await
+                .map_err(|e| e.to_string()),
+            Task::ExpireTickets => expire_tickets().await,
+            // a discrepancy is recorded in the report, not treated as a failed run
+            Task::Reconcile => reconcile::run_reconciliation()
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Released on drop, which includes ic-cdk dropping the future of a task that trapped.
+struct RunningGuard(Task);
+
+impl RunningGuard {
+    fn new(task: Task) -> Option<Self> {
+        RUNNING.with(|r| r.borrow_mut().insert(task)).then(|| RunningGuard(task))
+    }
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|r| r.borrow_mut().remove(&self.0));
+    }
+}
+
+fn with_status<R>(task: Task, f: impl FnOnce(&mut TaskStatus) -> R) -> R {
+    SCHEDULER.with(|s| {
+        let mut state = s.borrow_mut();
+        let status = state.tasks.entry(task).or_insert_with(|| TaskStatus {
+            task,
+            interval_seconds: task.default_interval_seconds(),
+            last_started_at: None,
+            last_succeeded_at: None,
+            last_error: None,
+            consecutive_failures: 0,
+            next_run_at: None,
+        });
+        f(status)
+    })
+}
+
+fn next_delay(status: &TaskStatus) -> Duration {
+    let factor = 1u64 << status.consecutive_failures.min(16);
+    let backoff = status.interval_seconds.saturating_mul(factor).min(MAX_BACKOFF_SECONDS);
+    Duration::from_secs(backoff.max(status.interval_seconds))
+}
+
+fn schedule(task: Task, delay: Duration) {
+    let timer = ic_cdk_timers::set_timer(delay, move || ic_cdk::spawn(run(task)));
+    with_status(task, |s| s.next_run_at = Some(ic_cdk::api::time() + delay.as_nanos() as u64));
+    if let Some(previous) = TIMERS.with(|t| t.borrow_mut().insert(task, timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+async fn run(task: Task) {
+    let Some(_running) = RunningGuard::new(task) else {
+        schedule(task, with_status(task, |s| Duration::from_secs(s.interval_seconds)));
+        return;
+    };
+    // Armed before the first await as if this run failed, so the task keeps
+    // going even if it traps; replaced below once the run completes.
+    let fallback = with_status(task, |s| {
+        s.last_started_at = Some(ic_cdk::api::time());
+        next_delay(&TaskStatus {
+            consecutive_failures: s.consecutive_failures + 1,
+            ..s.clone()
+        })
+    });
+    schedule(task, fallback);
+
+    let result = task.run().await;
+    let delay = with_status(task, |s| {
+        match result {
+            Ok(()) => {
+                s.last_succeeded_at = Some(ic_cdk::api::time());
+                s.consecutive_failures = 0;
+            }
+            Err(e) => {
+                ic_cdk::println!("{:?} failed: {}", task, e);
+                s.last_error = Some(e);
+                s.consecutive_failures += 1;
+            }
+        }
+        next_delay(s)
+    });
+    schedule(task, delay);
+}
+
+/// Arms a timer for every task; runs from both `init` and `post_upgrade`.
+pub fn start() {
+    for task in Task::ALL {
+        let interval = with_status(task, |s| Duration::from_secs(s.interval_seconds));
+        schedule(task, interval);
+    }
+}
+
+pub(crate) fn take_scheduler_state() -> SchedulerState {
+    SCHEDULER.with(|s| s.take())
+}
+
+pub(crate) fn restore_scheduler_state(state: SchedulerState) {
+    SCHEDULER.with(|s| *s.borrow_mut() = state);
+}
+
+async fn poll_confirmations() -> Result<(), String> {
+    // round-robin over signed tickets so a long backlog is eventually covered
+    let signed = tickets::with_status(TicketStatus::Signed);
+    let cursor = POLL_CURSOR.with(|c| c.get());
+    let pending: Vec<u64> = signed
+        .iter()
+        .filter(|t| t.id >= cursor)
+        .chain(signed.iter().filter(|t| t.id < cursor))
+        .take(MAX_TICKETS_PER_RUN)
+        .map(|t| t.id)
+        .collect();
+    POLL_CURSOR.with(|c| c.set(pending.last().map(|id| id + 1).unwrap_or(0)));
+
+    let mut errors = Vec::new();
+    for id in pending {
+        match crate::receipt_exists(id).await {
+            Ok(true) => {
+                // may have been settled by another call during the outcall
+                if tickets::get(id).map(|t| t.status) == Some(TicketStatus::Signed) {
+                    tickets::set_status(id, TicketStatus::Redeemed);
+                }
+            }
+            Ok(false) => {}
+            Err(e) => errors.push(format!("ticket {}: {}", id, e)),
+        }
+    }
+    join_errors(errors)
+}
+
+async fn expire_tickets() -> Result<(), String> {
+    // round-robin like `poll_confirmations`, so tickets that keep failing to
+    // settle do not hold back the ones after them
+    let now = tickets::now_seconds();
+    let refundable = tickets::refundable(now);
+    let cursor = SCHEDULER.with(|s| s.borrow().expire_cursor.unwrap_or(0));
+    let expired: Vec<u64> = refundable
+        .iter()
+        .filter(|id| **id >= cursor)
+        .chain(refundable.iter().filter(|id| **id < cursor))
+        .take(MAX_TICKETS_PER_RUN)
+        .copied()
+        .collect();
+    SCHEDULER.with(|s| s.borrow_mut().expire_cursor = expired.last().map(|id| id + 1));
+
+    let mut errors = Vec::new();
+    for id in expired {
+        if let Err(e) = crate::settle_expired_ticket(id).await {
+            errors.push(format!("ticket {}: {}", id, e));
+        }
+    }
+    batching::prune_batches(&tickets::prune_settled(tickets::now_seconds()));
+    join_errors(errors)
+}
+
+fn join_errors(errors: Vec<String>) -> Result<(), String> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+#[ic_cdk::query]
+fn get_scheduler_status() -> Vec<TaskStatus> {
+    Task::ALL.iter().map(|task| with_status(*task, |s| s.clone())).collect()
+}
+
+/// Changes how often `task` runs. A task that is currently failing keeps backing off from the new interval.
+#[ic_cdk::update(guard = "is_admin")]
+fn set_task_interval(task: Task, interval_seconds: u64) -> Result<(), BridgeError> {
+    if !(MIN_INTERVAL_SECONDS..=MAX_INTERVAL_SECONDS).contains(&interval_seconds) {
+        return Err(BridgeError::InvalidArgument(format!(
+            "interval must be between {} and {} seconds",
+            MIN_INTERVAL_SECONDS, MAX_INTERVAL_SECONDS
+        )));
+    }
+    let delay = with_status(task, |s| {
+        s.interval_seconds = interval_seconds;
+        next_delay(s)
+    });
+    // a running task reschedules itself with the new interval when it finishes
+    if !RUNNING.with(|r| r.borrow().contains(&task)) {
+        schedule(task, delay);
+    }
+    Ok(())
+}
@@ -0,0 +1,102 @@
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use sha2::{Digest, Sha256};
+
+const PDA_MARKER: &[u8] = b"ProgramDerivedAddress";
+pub(crate) const TOKEN_PROGRAM_ID: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
+const ASSOCIATED_TOKEN_PROGRAM_ID: &str = "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
+/// Mint of wrapped SOL; its vault holds the SOL that `withdraw_sol` pays out.
+pub(crate) const NATIVE_MINT: &str = "So11111111111111111111111111111111111111112";
+
+pub fn decode_pubkey(address: &str) -> Result<[u8; 32], String> {
+    bs58::decode(address)
+        .into_vec()
+        .map_err(|e| format!("{} is not base58: {}", address, e))?
+        .try_into()
+        .map_err(|_| format!("{} is not a 32-byte Solana address", address))
+}
+
+/// Same derivation as `Pubkey::create_program_address`: the address must not lie on the Ed25519 curve.
+fn create_program_address(seeds: &[&[u8]], program_id: &[u8; 32]) -> Option<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    for seed in seeds {
+        hasher.update(seed);
+    }
+    hasher.update(program_id);
+    hasher.update(PDA_MARKER);
+    let hash: [u8; 32] = hasher.finalize().into();
+
+    if CompressedEdwardsY(hash).decompress().is_some() {
+        None
+    } else {
+        Some(hash)
+    }
+}
+
+/// Same derivation as `Pubkey::find_program_address`, returning the address and its bump.
+fn find_program_address(seeds: &[&[u8]], program_id: &[u8; 32]) -> Option<([u8; 32], u8)> {
+    (0..=u8::MAX).rev().find_map(|bump| {
+        let bump_seed = [bump];
+        let mut seeds_with_bump = seeds.to_vec();
+        seeds_with_bump.push(&bump_seed);
+        create_program_address(&seeds_with_bump, program_id).map(|address| (address, bump))
+    })
+}
+
+/// Address of the `Receipt` account the vault program creates when the ticket with `nonce` is redeemed.
+pub fn receipt_address(program_id: &str, nonce: u64) -> Result<String, String> {
+    let program_id = decode_pubkey(program_id)?;
+    let (address, _) = find_program_address(&[b"receipt", &nonce.to_le_bytes()], &program_id)
+        .ok_or_else(|| "no valid bump for the receipt address".to_string())?;
+    Ok(bs58::encode(address).into_string())
+}
+
+/// Associated token account of `wallet` for `mint` under the SPL token program.
+pub fn associated_token_address(wallet: &str, mint: &str) -> Result<String, String> {
+    let wallet = decode_pubkey(wallet)?;
+    let mint = decode_pubkey(mint)?;
+    let token_program = decode_pubkey(TOKEN_PROGRAM_ID)?;
+    let (address, _) = find_program_address(
+        &[&wallet, &token_program, &mint],
+        &decode_pubkey(ASSOCIATED_TOKEN_PROGRAM_ID)?,
+    )
+    .ok_or_else(|| "no valid bump for the associated token address".to_string())?;
+    Ok(bs58::encode(address).into_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference addresses from `Pubkey::find_program_address` and
+    // `spl_associated_token_account::get_associated_token_address`.
+    const PROGRAM_ID: &str = "JAd7tStmfYcnhhc1Sh2CX8n99Hssk4rDVTHwu7KmHQ9S";
+    const WALLET: &str = "9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM";
+    const USDC_MINT: &str = "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v";
+
+    #[test]
+    fn derives_the_receipt_address() {
+        assert_eq!(
+            receipt_address(PROGRAM_ID, 42).unwrap(),
+            "5ViZjP2swFKQ16vwxgXtdFx71mvbjwUeeKkhRMHxRSxD"
+        );
+    }
+
+    #[test]
+    fn derives_associated_token_addresses() {
+        assert_eq!(
+            associated_token_address(WALLET, USDC_MINT).unwrap(),
+            "FGETo8T8wMcN2wCjav8VK6eh3dLk63evNDPxzLSJra8B"
+        );
+        assert_eq!(
+            associated_token_address(WALLET, NATIVE_MINT).unwrap(),
+            "8LjUgMjzZuHj8VdyxzkmLLQVmW4C3gd56md1nLd76TNW"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_addresses() {
+        assert!(decode_pubkey("not base58 0OIl").is_err());
+        assert!(decode_pubkey("3yZe7d").is_err());
+        assert!(associated_token_address(WALLET, "3yZe7d").is_err());
+    }
+}
@@ -0,0 +1,424 @@
+use candid::Nat;
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+// Public endpoints per cluster, used unless the canister is configured with its own providers.
+pub const MAINNET_PROVIDERS: &[&str] = &[
+    "https://api.mainnet-beta.solana.com",
+    "https://rpc.ankr.com/solana",
+    "https://solana-rpc.publicnode.com",
+];
+pub const DEVNET_PROVIDERS: &[&str] = &[
+    "https://api.devnet.solana.com",
+    "https://rpc.ankr.com/solana_devnet",
+];
+pub const TESTNET_PROVIDERS: &[&str] = &["https://api.testnet.solana.com"];
+
+const COMMITMENT: &str = "finalized";
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+// Number of nodes on a regular application subnet, used for outcall pricing.
+const SUBNET_SIZE: u128 = 13;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AccountInfo {
+    pub owner: String,
+    // `[<base64 data>, "base64"]`
+    pub data: (String, String),
+}
+
+#[derive(Deserialize, Debug)]
+struct RpcContextValue<T> {
+    value: T,
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct JsonRpcResponse {
+    result: Option<Value>,
+    error: Option<JsonRpcError>,
+}
+
+/// Something that can POST a JSON-RPC body to a provider and hand back the raw response body.
+pub(crate) trait RpcTransport {
+    async fn post(&self, url: &str, body: Vec<u8>, max_response_bytes: u64) -> Result<Vec<u8>, String>;
+}
+
+/// Sends requests through the management canister's `http_request`, with
+/// `transform_solana_rpc` stripping everything that differs between replicas.
+pub(crate) struct HttpOutcallTransport;
+
+impl RpcTransport for HttpOutcallTransport {
+    async fn post(&self, url: &str, body: Vec<u8>, max_response_bytes: u64) -> Result<Vec<u8>, String> {
+        let cycles = outcall_cycles(url.len() as u64 + body.len() as u64, max_response_bytes);
+        let request = CanisterHttpRequestArgument {
+            url: url.to_string(),
+            max_response_bytes: Some(max_response_bytes),
+            method: HttpMethod::POST,
+            headers: vec![HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            }],
+            body: Some(body),
+            transform: Some(TransformContext::from_name(
+                "transform_solana_rpc".to_string(),
+                vec![],
+            )),
+        };
+
+        let (response,) = http_request(request, cycles)
+            .await
+            .map_err(|(code, msg)| format!("http_request to {} failed: {:?} {}", url, code, msg))?;
+
+        if response.status != Nat::from(200u32) {
+            return Err(format!("{} returned status {}", url, response.status));
+        }
+        Ok(response.body)
+    }
+}
+
+/// Returns canned `result` values per (url, method), for driving the client in unit tests.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MockTransport {
+    responses: std::collections::HashMap<(String, String), Value>,
+}
+
+#[cfg(test)]
+impl MockTransport {
+    pub fn respond(mut self, url: &str, method: &str, result: Value) -> Self {
+        self.responses
+            .insert((url.to_string(), method.to_string()), result);
+        self
+    }
+}
+
+#[cfg(test)]
+impl RpcTransport for MockTransport {
+    async fn post(&self, url: &str, body: Vec<u8>, _max_response_bytes: u64) -> Result<Vec<u8>, String> {
+        let request: Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+        let method = request["method"].as_str().unwrap_or_default().to_string();
+        let result = self
+            .responses
+            .get(&(url.to_string(), method))
+            .ok_or_else(|| format!("no mock response for {}", url))?;
+        Ok(serde_json::to_vec(&json!({ "jsonrpc": "2.0", "id": 1, "result": result })).unwrap())
+    }
+}
+
+/// Solana JSON-RPC client that sends every request to all providers and only
+/// accepts a result that at least `quorum` of them agree on.
+pub(crate) struct SolanaRpcClient<T: RpcTransport> {
+    providers: Vec<String>,
+    quorum: usize,
+    transport: T,
+}
+
+impl SolanaRpcClient<HttpOutcallTransport> {
+    pub fn new(providers: Vec<String>, quorum: usize) -> Self {
+        Self::with_transport(providers, quorum, HttpOutcallTransport)
+    }
+}
+
+impl<T: RpcTransport> SolanaRpcClient<T> {
+    pub fn with_transport(providers: Vec<String>, quorum: usize, transport: T) -> Self {
+        let quorum = quorum.clamp(1, providers.len().max(1));
+        Self {
+            providers,
+            quorum,
+            transport,
+        }
+    }
+
+    pub async fn get_account_info(&self, address: &str) -> Result<Option<AccountInfo>, String> {
+        let reply: RpcContextValue<Option<AccountInfo>> = self
+            .call(
+                "getAccountInfo",
+                json!([address, { "encoding": "base64", "commitment": COMMITMENT }]),
+            )
+            .await?;
+        Ok(reply.value)
+    }
+
+    async fn call<R: DeserializeOwned>(&self, method: &str, params: Value) -> Result<R, String> {
+        let body = serde_json::to_vec(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        }))
+        .map_err(|e| e.to_string())?;
+
+        // (result, number of providers that returned it)
+        let mut tally: Vec<(Value, usize)> = vec![];
+        let mut errors: Vec<String> = vec![];
+        for provider in &self.providers {
+            let result = self
+                .transport
+                .post(provider, body.clone(), DEFAULT_MAX_RESPONSE_BYTES)
+                .await
+                .and_then(|bytes| parse_rpc_result(&bytes));
+            match result {
+                Ok(value) => match tally.iter_mut().find(|(v, _)| *v == value) {
+                    Some((_, count)) => *count += 1,
+                    None => tally.push((value, 1)),
+                },
+                Err(e) => errors.push(format!("{}: {}", provider, e)),
+            }
+        }
+
+        match tally.into_iter().max_by_key(|(_, count)| *count) {
+            Some((value, count)) if count >= self.quorum => {
+                serde_json::from_value(value).map_err(|e| format!("{} decode failed: {}", method, e))
+            }
+            best => Err(format!(
+                "{}: no quorum ({} of {} agreed, {} required); errors: [{}]",
+                method,
+                best.map(|(_, count)| count).unwrap_or(0),
+                self.providers.len(),
+                self.quorum,
+                errors.join("; ")
+            )),
+        }
+    }
+}
+
+fn parse_rpc_result(bytes: &[u8]) -> Result<Value, String> {
+    let response: JsonRpcResponse =
+        serde_json::from_slice(bytes).map_err(|e| format!("invalid JSON-RPC response: {}", e))?;
+    if let Some(error) = response.error {
+        return Err(format!("rpc error {}: {}", error.code, error.message));
+    }
+    // Null results ("not found") are legitimate.
+    let mut result = response.result.unwrap_or(Value::Null);
+    strip_context(&mut result);
+    Ok(result)
+}
+
+// `context.slot` differs between providers and between replicas even when the
+// payload is identical, so it must not take part in any comparison.
+fn strip_context(value: &mut Value) {
+    if let Some(object) = value.as_object_mut() {
+        object.remove("context");
+    }
+}
+
+/// Cycles attached to an outcall, following the published pricing for a 13-node subnet.
+fn outcall_cycles(request_bytes: u64, max_response_bytes: u64) -> u128 {
+    (3_000_000 + 60_000 * SUBNET_SIZE) * SUBNET_SIZE
+        + 400 * SUBNET_SIZE * request_bytes as u128
+        + 800 * SUBNET_SIZE * max_response_bytes as u128
+}
+
+/// Normalises a provider response so that all replicas see identical bytes:
+/// headers are dropped and the body is reduced to `result`/`error` without `context`.
+#[ic_cdk::query]
+fn transform_solana_rpc(args: TransformArgs) -> HttpResponse {
+    let body = match serde_json::from_slice::<Value>(&args.response.body) {
+        Ok(mut response) => {
+            if let Some(result) = response.get_mut("result") {
+                strip_context(result);
+            }
+            let normalized = json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "result": response.get("result").cloned(),
+                "error": response.get("error").cloned(),
+            });
+            serde_json::to_vec(&normalized).unwrap_or_default()
+        }
+        Err(_) => vec![],
+    };
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::task::{Context, Poll, Waker};
+
+    const A: &str = "https://a.example";
+    const B: &str = "https://b.example";
+    const C: &str = "https://c.example";
+    const ADDRESS: &str = "JAd7tStmfYcnhhc1Sh2CX8n99Hssk4rDVTHwu7KmHQ9S";
+
+    // The mock transport never suspends, so a single poll drives a call to completion.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("mock transport suspended"),
+        }
+    }
+
+    fn account(owner: &str, slot: u64) -> Value {
+        json!({
+            "context": { "slot": slot },
+            "value": {
+                "lamports": 1_000_000,
+                "owner": owner,
+                "data": ["AAEC", "base64"],
+                "executable": false,
+                "rentEpoch": 0
+            }
+        })
+    }
+
+    fn client(transport: MockTransport, quorum: usize) -> SolanaRpcClient<MockTransport> {
+        let providers = vec![A.to_string(), B.to_string(), C.to_string()];
+        SolanaRpcClient::with_transport(providers, quorum, transport)
+    }
+
+    #[test]
+    fn quorum_accepts_majority_result_despite_different_slots() {
+        let transport = MockTransport::default()
+            .respond(A, "getAccountInfo", account("owner-1", 10))
+            .respond(B, "getAccountInfo", account("owner-1", 11))
+            .respond(C, "getAccountInfo", account("owner-2", 12));
+
+        let info = block_on(client(transport, 2).get_account_info(ADDRESS))
+            .unwrap()
+            .expect("account exists");
+        assert_eq!(info.owner, "owner-1");
+        assert_eq!(info.data, ("AAEC".to_string(), "base64".to_string()));
+    }
+
+    #[test]
+    fn quorum_tolerates_failing_provider() {
+        let transport = MockTransport::default()
+            .respond(A, "getAccountInfo", account("owner-1", 10))
+            .respond(C, "getAccountInfo", account("owner-1", 10));
+
+        let info = block_on(client(transport, 2).get_account_info(ADDRESS)).unwrap();
+        assert_eq!(info.unwrap().owner, "owner-1");
+    }
+
+    #[test]
+    fn disagreement_below_quorum_is_an_error() {
+        let transport = MockTransport::default()
+            .respond(A, "getAccountInfo", account("owner-1", 10))
+            .respond(B, "getAccountInfo", account("owner-2", 10))
+            .respond(C, "getAccountInfo", account("owner-3", 10));
+
+        let err = block_on(client(transport, 2).get_account_info(ADDRESS)).unwrap_err();
+        assert!(err.contains("no quorum (1 of 3 agreed, 2 required)"), "{}", err);
+    }
+
+    #[test]
+    fn quorum_is_clamped_to_provider_count() {
+        let transport = MockTransport::default()
+            .respond(A, "getAccountInfo", account("owner-1", 10))
+            .respond(B, "getAccountInfo", account("owner-1", 10))
+            .respond(C, "getAccountInfo", account("owner-1", 10));
+
+        assert!(block_on(client(transport, 10).get_account_info(ADDRESS)).is_ok());
+    }
+
+    #[test]
+    fn missing_account_decodes_as_not_found() {
+        let missing = json!({ "context": { "slot": 10 }, "value": null });
+        let transport = MockTransport::default()
+            .respond(A, "getAccountInfo", missing.clone())
+            .respond(B, "getAccountInfo", missing.clone())
+            .respond(C, "getAccountInfo", missing);
+
+        let info = block_on(client(transport, 2).get_account_info(ADDRESS)).unwrap();
+        assert!(info.is_none());
+    }
+
+    #[test]
+    fn parse_rpc_result_reports_rpc_errors() {
+        let body = br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32602,"message":"Invalid param"}}"#;
+        assert_eq!(parse_rpc_result(body).unwrap_err(), "rpc error -32602: Invalid param");
+    }
+
+    #[test]
+    fn parse_rpc_result_handles_null_and_missing_result() {
+        assert_eq!(
+            parse_rpc_result(br#"{"jsonrpc":"2.0","id":1,"result":null}"#).unwrap(),
+            Value::Null
+        );
+        assert_eq!(parse_rpc_result(br#"{"jsonrpc":"2.0","id":1}"#).unwrap(), Value::Null);
+    }
+
+    #[test]
+    fn parse_rpc_result_strips_context_and_rejects_garbage() {
+        let response = json!({ "jsonrpc": "2.0", "id": 1, "result": account("owner-1", 7) });
+        let result = parse_rpc_result(&serde_json::to_vec(&response).unwrap()).unwrap();
+        assert!(result.get("context").is_none());
+        assert_eq!(result["value"]["owner"], "owner-1");
+
+        assert!(parse_rpc_result(b"<html>bad gateway</html>")
+            .unwrap_err()
+            .starts_with("invalid JSON-RPC response"));
+    }
+
+    #[test]
+    fn transform_normalizes_replica_dependent_parts() {
+        let response = |slot: u64, request_id: u64| HttpResponse {
+            status: Nat::from(200u32),
+            headers: vec![HttpHeader {
+                name: "date".to_string(),
+                value: format!("replica {}", slot),
+            }],
+            body: serde_json::to_vec(&json!({
+                "jsonrpc": "2.0",
+                "id": request_id,
+                "result": account("owner-1", slot),
+            }))
+            .unwrap(),
+        };
+        let transform = |response| {
+            transform_solana_rpc(TransformArgs {
+                response,
+                context: vec![],
+            })
+        };
+
+        let first = transform(response(10, 1));
+        let second = transform(response(11, 2));
+        assert_eq!(first, second);
+        assert!(first.headers.is_empty());
+        assert_eq!(first.status, Nat::from(200u32));
+        assert_eq!(parse_rpc_result(&first.body).unwrap()["value"]["owner"], "owner-1");
+    }
+
+    #[test]
+    fn transform_keeps_errors_and_drops_unparseable_bodies() {
+        let error = transform_solana_rpc(TransformArgs {
+            response: HttpResponse {
+                status: Nat::from(200u32),
+                headers: vec![],
+                body: br#"{"jsonrpc":"2.0","id":5,"error":{"code":-32005,"message":"Node is behind"}}"#.to_vec(),
+            },
+            context: vec![],
+        });
+        assert_eq!(parse_rpc_result(&error.body).unwrap_err(), "rpc error -32005: Node is behind");
+
+        let garbage = transform_solana_rpc(TransformArgs {
+            response: HttpResponse {
+                status: Nat::from(502u32),
+                headers: vec![],
+                body: b"bad gateway".to_vec(),
+            },
+            context: vec![],
+        });
+        assert!(garbage.body.is_empty());
+        assert_eq!(garbage.status, Nat::from(502u32));
+    }
+}
@@ -1,11 +1,17 @@
+use crate::config::{read_config, set_config};
+use crate::error::BridgeError;
+use crate::roles::{is_admin, is_operator};
 use candid::{CandidType, Principal};
 use ic_cdk::{query, update};
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
 use std::{convert::{TryFrom, TryInto}};
 
 type CanisterId = Principal;
 
-#[derive(CandidType, Serialize, Deserialize, Debug, Copy, Clone)]
+#[derive(CandidType, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 pub enum SchnorrAlgorithm {
     #[serde(rename = "bip340secp256k1")]
     Bip340Secp256k1,
@@ -41,12 +47,26 @@ struct ManagementCanisterSchnorrPublicKeyReply {
     pub chain_code: Vec<u8>,
 }
 
-#[derive(CandidType, Serialize, Debug, Clone)]
-struct SchnorrKeyId {
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SchnorrKeyId {
     pub algorithm: SchnorrAlgorithm,
     pub name: String,
 }
 
+impl SchnorrKeyId {
+    /// Everything derived from the key ends up on Solana as an address or an
+    /// Ed25519 signature, so BIP340 keys are of no use to the bridge.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("schnorr_key_id.name must not be empty".to_string());
+        }
+        if self.algorithm != SchnorrAlgorithm::Ed25519 {
+            return Err("schnorr_key_id.algorithm must be ed25519".to_string());
+        }
+        Ok(())
+    }
+}
+
 #[derive(CandidType, Serialize, Debug)]
 struct ManagementCanisterSignatureRequest {
     pub message: Vec<u8>,
@@ -59,27 +79,308 @@ struct ManagementCanisterSignatureReply {
     pub signature: Vec<u8>,
 }
 
-#[update]
-// async fn public_key(algorithm: SchnorrAlgorithm) -> Result<PublicKeyReply, String> {
-pub async fn solana_address() -> Result<String, String> {
+/// The bridge public key together with the key id it was derived from, so a
+/// config change can be detected.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct CachedPublicKey {
+    pub key_id: SchnorrKeyId,
+    pub public_key: Vec<u8>,
+}
+
+/// A bridge signing key: the threshold key it lives under, its rotation epoch and its public key.
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct BridgeKeyInfo {
+    pub key_id: SchnorrKeyId,
+    pub epoch: u32,
+    pub public_key_hex: String,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct BridgeKeys {
+    pub current: Option<BridgeKeyInfo>,
+    /// Key replaced by the last rotation; its signatures stay valid on Solana during the overlap window.
+    pub previous: Option<BridgeKeyInfo>,
+    /// Key prepared for the next rotation, not used for signing yet.
+    pub pending: Option<BridgeKeyInfo>,
+}
+
+/// Rotation bookkeeping persisted across upgrades.
+#[derive(CandidType, Deserialize, Debug, Clone, Default)]
+pub struct KeyRotationState {
+    pub epoch: u32,
+    pub previous: Option<BridgeKeyInfo>,
+    pub pending: Option<BridgeKeyInfo>,
+}
+
+thread_local! {
+    static BRIDGE_PUBLIC_KEY: RefCell<Option<CachedPublicKey>> = RefCell::new(None);
+    static KEY_ROTATION: RefCell<KeyRotationState> = RefCell::new(KeyRotationState::default());
+    // user -> derived Solana address, and the reverse index used to attribute deposits
+    static USER_SOLANA_ADDRESSES: RefCell<HashMap<Principal, String>> = RefCell::new(HashMap::new());
+    static SOLANA_ADDRESS_OWNERS: RefCell<HashMap<String, Principal>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn cached_bridge_public_key() -> Option<CachedPublicKey> {
+    BRIDGE_PUBLIC_KEY.with(|k| k.borrow().clone())
+}
+
+/// Installs a cached key restored from stable memory. Keys derived from a key
+/// id other than the configured one are dropped, together with the user
+/// addresses derived from it.
+pub(crate) fn restore_bridge_public_key(cached: Option<CachedPublicKey>) {
+    let key_id = read_config(|c| c.schnorr_key_id.clone());
+    match cached {
+        Some(cached) if cached.key_id == key_id => {
+            BRIDGE_PUBLIC_KEY.with(|k| *k.borrow_mut() = Some(cached));
+        }
+        Some(_) => {
+            BRIDGE_PUBLIC_KEY.with(|k| *k.borrow_mut() = None);
+            restore_user_solana_addresses(HashMap::new());
+        }
+        None => BRIDGE_PUBLIC_KEY.with(|k| *k.borrow_mut() = None),
+    }
+}
+
+/// Returns the bridge public key, asking the management canister only if it is not cached yet.
+pub(crate) async fn bridge_public_key() -> Result<Vec<u8>, BridgeError> {
+    if let Some(cached) = cached_bridge_public_key() {
+        return Ok(cached.public_key);
+    }
+    let key_id = read_config(|c| c.schnorr_key_id.clone());
+    let epoch = current_epoch();
+    let public_key = fetch_public_key(key_id.clone(), bridge_derivation_path_for(epoch)).await?;
+    // The key may have been changed or rotated while we were waiting; only cache a key that still matches.
+    if read_config(|c| c.schnorr_key_id == key_id) && current_epoch() == epoch {
+        BRIDGE_PUBLIC_KEY.with(|k| {
+            *k.borrow_mut() = Some(CachedPublicKey {
+                key_id,
+                public_key: public_key.clone(),
+            })
+        });
+    }
+    Ok(public_key)
+}
+
+/// Drops cached keys that no longer match the configured key id and fetches the new one.
+pub(crate) fn refresh_after_config_change() {
+    restore_bridge_public_key(cached_bridge_public_key());
+    schedule_bridge_public_key_fetch();
+}
+
+/// Fetches the bridge key in the background right after install or upgrade.
+pub(crate) fn schedule_bridge_public_key_fetch() {
+    if cached_bridge_public_key().is_some() {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::ZERO, || {
+        ic_cdk::spawn(async {
+            if let Err(e) = bridge_public_key().await {
+                ic_cdk::println!("failed to fetch the bridge public key: {}", e);
+            }
+        })
+    });
+}
+
+pub(crate) fn current_epoch() -> u32 {
+    KEY_ROTATION.with(|r| r.borrow().epoch)
+}
+
+fn current_bridge_key_info() -> Option<BridgeKeyInfo> {
+    cached_bridge_public_key().map(|cached| BridgeKeyInfo {
+        key_id: cached.key_id,
+        epoch: current_epoch(),
+        public_key_hex: hex::encode(cached.public_key),
+    })
+}
+
+pub(crate) fn key_rotation_state() -> KeyRotationState {
+    KEY_ROTATION.with(|r| r.borrow().clone())
+}
+
+pub(crate) fn restore_key_rotation_state(state: KeyRotationState) {
+    KEY_ROTATION.with(|r| *r.borrow_mut() = state);
+}
+
+/// First step of a key rotation: derives the key of the next epoch (optionally
+/// under another threshold key) without using it yet, so it can be added to
+/// the Solana signer set before the canister switches to it.
+#[update(guard = "is_admin")]
+async fn prepare_bridge_key_rotation(new_key_id: Option<SchnorrKeyId>) -> Result<BridgeKeyInfo, BridgeError> {
+    let key_id = new_key_id.unwrap_or_else(|| read_config(|c| c.schnorr_key_id.clone()));
+    key_id.validate().map_err(BridgeError::InvalidConfig)?;
+    let epoch = current_epoch() + 1;
+    let public_key = fetch_public_key(key_id.clone(), bridge_derivation_path_for(epoch)).await?;
+
+    let pending = BridgeKeyInfo {
+        key_id,
+        epoch,
+        public_key_hex: hex::encode(public_key),
+    };
+    KEY_ROTATION.with(|r| r.borrow_mut().pending = Some(pending.clone()));
+    Ok(pending)
+}
+
+/// Second step of a key rotation: signs with the prepared key from now on.
+/// Call it once the Solana program accepts the new key (`rotate_signers`).
+///
+/// If the rotation changes the threshold key id, user deposit addresses are
+/// derived anew; funds left on the old addresses must be swept beforehand.
+#[update(guard = "is_admin")]
+async fn commit_bridge_key_rotation() -> Result<BridgeKeyInfo, BridgeError> {
+    let prepared = KEY_ROTATION
+        .with(|r| r.borrow().pending.clone())
+        .ok_or_else(|| BridgeError::InvalidState("No key rotation has been prepared".to_string()))?;
+    if prepared.epoch != current_epoch() + 1 {
+        return Err(BridgeError::InvalidState(
+            "Prepared key rotation is stale, prepare it again".to_string(),
+        ));
+    }
+    let public_key = hex::decode(&prepared.public_key_hex).map_err(|e| BridgeError::Internal(e.to_string()))?;
 
-    let publickey_result = schnorr_public_key().await;
-    let ed25519_public_key_hex = hex::decode(publickey_result.unwrap().public_key_hex).unwrap();
+    // Fetched rather than read from the cache so the outgoing key is recorded even if it was never cached.
+    let key_id = read_config(|c| c.schnorr_key_id.clone());
+    let previous_public_key = bridge_public_key().await?;
+    let previous = BridgeKeyInfo {
+        key_id: key_id.clone(),
+        epoch: current_epoch(),
+        public_key_hex: hex::encode(previous_public_key),
+    };
+
+    // Another rotation or config change may have landed while we were waiting.
+    let pending = KEY_ROTATION
+        .with(|r| {
+            let mut rotation = r.borrow_mut();
+            let unchanged = rotation.epoch == previous.epoch
+                && rotation.pending.as_ref().map(|p| (p.epoch, &p.public_key_hex))
+                    == Some((prepared.epoch, &prepared.public_key_hex));
+            if unchanged && read_config(|c| c.schnorr_key_id == key_id) {
+                rotation.epoch = prepared.epoch;
+                rotation.previous = Some(previous);
+                rotation.pending.take()
+            } else {
+                None
+            }
+        })
+        .ok_or_else(|| BridgeError::InvalidState("Key rotation changed concurrently, retry".to_string()))?;
+
+    let mut config = read_config(|c| c.clone());
+    if config.schnorr_key_id != pending.key_id {
+        config.schnorr_key_id = pending.key_id.clone();
+        set_config(config);
+        restore_user_solana_addresses(HashMap::new());
+    }
+    BRIDGE_PUBLIC_KEY.with(|k| {
+        *k.borrow_mut() = Some(CachedPublicKey {
+            key_id: pending.key_id.clone(),
+            public_key,
+        })
+    });
+    Ok(pending)
+}
+
+#[query]
+fn get_bridge_keys() -> BridgeKeys {
+    let rotation = key_rotation_state();
+    BridgeKeys {
+        current: current_bridge_key_info(),
+        previous: rotation.previous,
+        pending: rotation.pending,
+    }
+}
+
+pub(crate) fn take_user_solana_addresses() -> HashMap<Principal, String> {
+    SOLANA_ADDRESS_OWNERS.with(|o| o.borrow_mut().clear());
+    USER_SOLANA_ADDRESSES.with(|a| a.take())
+}
+
+/// Restores the user -> address map and rebuilds the reverse index from it.
+pub(crate) fn restore_user_solana_addresses(addresses: HashMap<Principal, String>) {
+    SOLANA_ADDRESS_OWNERS.with(|o| {
+        *o.borrow_mut() = addresses
+            .iter()
+            .map(|(user, address)| (address.clone(), *user))
+            .collect()
+    });
+    USER_SOLANA_ADDRESSES.with(|a| *a.borrow_mut() = addresses);
+}
 
-    let address = bs58::encode(ed25519_public_key_hex).into_string();
-    return Ok(address)
+/// Derivation path of the bridge's key in `epoch`, the one that signs outbound transfers.
+// Epoch 0 keeps the original empty path so the key of existing deployments does not change.
+fn bridge_derivation_path_for(epoch: u32) -> Vec<Vec<u8>> {
+    if epoch == 0 {
+        vec![]
+    } else {
+        vec![b"bridge".to_vec(), epoch.to_be_bytes().to_vec()]
+    }
+}
+
+/// Derivation path of the deposit key controlled by the canister on behalf of `user`.
+/// The leading tag keeps user keys disjoint from the bridge key.
+pub(crate) fn user_derivation_path(user: &Principal) -> Vec<Vec<u8>> {
+    vec![b"deposit".to_vec(), user.as_slice().to_vec()]
 }
 
+#[query]
+pub fn solana_address() -> Result<String, BridgeError> {
+    let cached = cached_bridge_public_key().ok_or(BridgeError::PublicKeyUnavailable)?;
+    Ok(bs58::encode(cached.public_key).into_string())
+}
+
+/// Derives (once) the caller's personal Solana deposit address.
 #[update]
-// async fn public_key(algorithm: SchnorrAlgorithm) -> Result<PublicKeyReply, String> {
-async fn schnorr_public_key() -> Result<PublicKeyReply, String> {
+async fn solana_deposit_address() -> Result<String, BridgeError> {
+    let user = ic_cdk::caller();
+    if user == Principal::anonymous() {
+        return Err(BridgeError::Unauthorized(
+            "Anonymous principal has no deposit address".to_string(),
+        ));
+    }
+    if let Some(address) = USER_SOLANA_ADDRESSES.with(|a| a.borrow().get(&user).cloned()) {
+        return Ok(address);
+    }
+
+    let key_id = read_config(|c| c.schnorr_key_id.clone());
+    let public_key = fetch_public_key(key_id, user_derivation_path(&user)).await?;
+    let address = bs58::encode(public_key).into_string();
+
+    USER_SOLANA_ADDRESSES.with(|a| a.borrow_mut().insert(user, address.clone()));
+    SOLANA_ADDRESS_OWNERS.with(|o| o.borrow_mut().insert(address.clone(), user));
+    Ok(address)
+}
+
+#[query]
+fn get_user_solana_address(user: Principal) -> Option<String> {
+    USER_SOLANA_ADDRESSES.with(|a| a.borrow().get(&user).cloned())
+}
+
+#[query]
+fn get_solana_address_owner(address: String) -> Option<Principal> {
+    SOLANA_ADDRESS_OWNERS.with(|o| o.borrow().get(&address).cloned())
+}
+
+#[query]
+fn schnorr_public_key() -> Result<PublicKeyReply, BridgeError> {
+    let cached = cached_bridge_public_key().ok_or(BridgeError::PublicKeyUnavailable)?;
+    Ok(PublicKeyReply {
+        public_key_hex: hex::encode(&cached.public_key),
+    })
+}
+
+/// Fetches the bridge public key now if it is not cached yet, e.g. when the
+/// background fetch after install failed.
+#[update(guard = "is_operator")]
+async fn fetch_bridge_public_key() -> Result<PublicKeyReply, BridgeError> {
+    let public_key = bridge_public_key().await?;
+    Ok(PublicKeyReply {
+        public_key_hex: hex::encode(&public_key),
+    })
+}
+
+async fn fetch_public_key(key_id: SchnorrKeyId, derivation_path: Vec<Vec<u8>>) -> Result<Vec<u8>, BridgeError> {
     let request = ManagementCanisterSchnorrPublicKeyRequest {
         canister_id: None,
-        derivation_path: vec![],
-        #[cfg(feature = "local")]
-        key_id: SchnorrKeyIds::TestKeyLocalDevelopment.to_key_id(SchnorrAlgorithm::Ed25519),
-        #[cfg(any(feature = "prod", feature = "dev"))]
-        key_id: SchnorrKeyIds::TestKey1.to_key_id(SchnorrAlgorithm::Ed25519),
+        derivation_path,
+        key_id,
     };
 
     let (res,): (ManagementCanisterSchnorrPublicKeyReply,) = ic_cdk::call(
@@ -88,22 +389,24 @@ async fn schnorr_public_key() -> Result<PublicKeyReply, String> {
         (request,),
     )
     .await
-    .map_err(|e| format!("schnorr_public_key failed {}", e.1))?;
+    .map_err(|e| BridgeError::call_failed("schnorr_public_key", e))?;
 
-    Ok(PublicKeyReply {
-        public_key_hex: hex::encode(&res.public_key),
-    })
+    Ok(res.public_key)
 }
 
-#[update]
-pub(crate) async fn schnorr_sign(message: String) -> Result<SignatureReply, String> {
+/// Signs `message` with the bridge key. The signature is checked against the
+/// bridge public key before it is returned.
+pub(crate) async fn schnorr_sign(message: String) -> Result<SignatureReply, BridgeError> {
+    let message = message.into_bytes();
+    let key_id = read_config(|c| c.schnorr_key_id.clone());
+    let epoch = current_epoch();
+    let public_key = bridge_public_key().await?;
+
     let internal_request = ManagementCanisterSignatureRequest {
-        message: message.as_bytes().to_vec(),
-        derivation_path: vec![],
-        #[cfg(feature = "local")]
-        key_id: SchnorrKeyIds::TestKeyLocalDevelopment.to_key_id(SchnorrAlgorithm::Ed25519),
-        #[cfg(any(feature = "prod", feature = "dev"))]
-        key_id: SchnorrKeyIds::TestKey1.to_key_id(SchnorrAlgorithm::Ed25519),    };
+        message: message.clone(),
+        derivation_path: bridge_derivation_path_for(epoch),
+        key_id: key_id.clone(),
+    };
 
     let (internal_reply,): (ManagementCanisterSignatureReply,) =
         ic_cdk::api::call::call_with_payment(
@@ -113,35 +416,81 @@ pub(crate) async fn schnorr_sign(message: String) -> Result<SignatureReply, Stri
             25_000_000_000,
         )
         .await
-        .map_err(|e| format!("sign_with_schnorr failed {e:?}"))?;
+        .map_err(|e| BridgeError::SigningFailed(format!("sign_with_schnorr failed {e:?}")))?;
+
+    // Never hand out a signature the Solana program would reject, e.g. because
+    // the key id changed or the key was rotated while the request was in flight.
+    if read_config(|c| c.schnorr_key_id != key_id) || current_epoch() != epoch {
+        return Err(BridgeError::SigningFailed("Bridge key changed while signing".to_string()));
+    }
+    verify_signature(key_id.algorithm, &public_key, &message, &internal_reply.signature)
+        .map_err(BridgeError::SigningFailed)?;
 
     Ok(SignatureReply {
         signature_hex: hex::encode(&internal_reply.signature),
     })
 }
 
+/// Checks `signature_hex` over `message` against the current bridge public key
+/// or, for tickets issued before the last rotation, the previous one.
+#[query]
+fn verify_bridge_signature(
+    message: String,
+    signature_hex: String,
+) -> Result<SignatureVerificationReply, BridgeError> {
+    let current = current_bridge_key_info().ok_or(BridgeError::PublicKeyUnavailable)?;
+    let signature = hex::decode(&signature_hex)
+        .map_err(|e| BridgeError::InvalidArgument(format!("Invalid signature hex: {}", e)))?;
+    let is_signature_valid = std::iter::once(current)
+        .chain(key_rotation_state().previous)
+        .any(|key| {
+            let public_key = hex::decode(&key.public_key_hex).unwrap_or_default();
+            verify_signature(key.key_id.algorithm, &public_key, message.as_bytes(), &signature).is_ok()
+        });
+    Ok(SignatureVerificationReply { is_signature_valid })
+}
+
+pub(crate) fn verify_signature(
+    algorithm: SchnorrAlgorithm,
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), String> {
+    match algorithm {
+        SchnorrAlgorithm::Ed25519 => verify_ed25519(public_key, message, signature),
+        SchnorrAlgorithm::Bip340Secp256k1 => verify_bip340_secp256k1(public_key, message, signature),
+    }
+}
+
+fn verify_ed25519(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), String> {
+    use ed25519_dalek::{Signature, Verifier, VerifyingKey};
 
-enum SchnorrKeyIds {
-    #[allow(unused)]
-    TestKeyLocalDevelopment,
-    #[allow(unused)]
-    TestKey1,
-    #[allow(unused)]
-    ProductionKey1,
+    let public_key: [u8; 32] = public_key
+        .try_into()
+        .map_err(|_| "Ed25519 public key must be 32 bytes".to_string())?;
+    let verifying_key =
+        VerifyingKey::from_bytes(&public_key).map_err(|e| format!("Invalid Ed25519 public key: {}", e))?;
+    let signature =
+        Signature::from_slice(signature).map_err(|e| format!("Invalid Ed25519 signature: {}", e))?;
+    verifying_key
+        .verify(message, &signature)
+        .map_err(|_| "Ed25519 signature verification failed".to_string())
 }
 
-impl SchnorrKeyIds {
-    fn to_key_id(&self, algorithm: SchnorrAlgorithm) -> SchnorrKeyId {
-        SchnorrKeyId {
-            algorithm,
-            name: match self {
-                Self::TestKeyLocalDevelopment => "dfx_test_key",
-                Self::TestKey1 => "test_key_1",
-                Self::ProductionKey1 => "key_1",
-            }
-            .to_string(),
-        }
+fn verify_bip340_secp256k1(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), String> {
+    use k256::schnorr::{Signature, VerifyingKey};
+
+    // The management canister returns a SEC1 compressed key; BIP340 only uses the x coordinate.
+    if public_key.len() != 33 {
+        return Err("BIP340 public key must be 33 bytes".to_string());
     }
+    let verifying_key = VerifyingKey::from_bytes(&public_key[1..])
+        .map_err(|e| format!("Invalid BIP340 public key: {}", e))?;
+    let signature =
+        Signature::try_from(signature).map_err(|e| format!("Invalid BIP340 signature: {}", e))?;
+    verifying_key
+        .verify_raw(message, &signature)
+        .map_err(|_| "BIP340 signature verification failed".to_string())
 }
 
 // In the following, we register a custom getrandom implementation because
@@ -0,0 +1,289 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// Margin on top of the expiry before a refund, covering drift between the IC
+/// clock and the Solana `Clock` sysvar and withdrawals that are not finalized yet.
+pub const REFUND_GRACE_SECONDS: i64 = 10 * 60;
+/// How long redeemed and refunded tickets stay queryable after their refund
+/// window before they are pruned.
+const SETTLED_RETENTION_SECONDS: i64 = 30 * 24 * 60 * 60;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TicketStatus {
+    /// Waiting for the next batch to be signed.
+    Queued,
+    /// Signature handed out, withdrawal possible on Solana until `expiry`.
+    Signed,
+    /// The nonce was consumed on Solana.
+    Redeemed,
+    /// Expired without being redeemed; the amount went back to the owner's bridge balance.
+    Refunded,
+}
+
+/// An ICP -> Solana transfer authorised by a bridge signature.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Ticket {
+    /// Also the nonce of the signed message.
+    pub id: u64,
+    pub owner: Principal,
+    pub amount: u64,
+    /// Solana token account receiving the funds, or the wallet for SOL tickets.
+    pub recipient: String,
+    /// Wallet whose associated token account `recipient` is, if the ticket was
+    /// requested for a wallet; relayers create the account when it is missing.
+    pub recipient_wallet: Option<String>,
+    /// Unix timestamp (seconds) after which the Solana program rejects the signature.
+    pub expiry: i64,
+    /// For batched tickets, the signature over the batch root.
+    pub signature_hex: String,
+    pub status: TicketStatus,
+    pub created_at: u64,
+    pub batch_id: Option<u64>,
+    /// Hex sibling hashes from the ticket's leaf up to the batch root.
+    pub merkle_proof: Option<Vec<String>>,
+    /// SPL mint for tickets paid for by burning its wrapped token, `None` for the pool token.
+    pub wrapped_mint: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct TicketState {
+    pub next_id: u64,
+    pub tickets: BTreeMap<u64, Ticket>,
+    /// Total of the pruned redeemed pool-token tickets, whose tokens stay in the pool.
+    pub pruned_bridged_out: Option<u64>,
+}
+
+thread_local! {
+    static TICKETS: RefCell<TicketState> = RefCell::new(TicketState::default());
+    // status -> ids, so the scheduler does not scan settled tickets; rebuilt on upgrade
+    static BY_STATUS: RefCell<BTreeMap<TicketStatus, BTreeSet<u64>>> = RefCell::new(BTreeMap::new());
+}
+
+impl Ticket {
+    pub fn is_expired(&self, now_seconds: i64) -> bool {
+        now_seconds > self.expiry
+    }
+
+    /// Tickets burning wrapped SOL are paid in lamports to a wallet by `withdraw_sol`.
+    pub fn pays_native_sol(&self) -> bool {
+        self.wrapped_mint.as_deref() == Some(crate::solana_pda::NATIVE_MINT)
+    }
+
+    /// Queued tickets count too: one may have been signed just before it was refunded.
+    pub fn is_refundable(&self, now_seconds: i64) -> bool {
+        matches!(self.status, TicketStatus::Queued | TicketStatus::Signed)
+            && now_seconds > self.expiry + REFUND_GRACE_SECONDS
+    }
+
+    fn is_prunable(&self, now_seconds: i64) -> bool {
+        matches!(self.status, TicketStatus::Redeemed | TicketStatus::Refunded)
+            && now_seconds > self.expiry + REFUND_GRACE_SECONDS + SETTLED_RETENTION_SECONDS
+    }
+}
+
+fn index(id: u64, from: Option<TicketStatus>, to: Option<TicketStatus>) {
+    BY_STATUS.with(|index| {
+        let mut index = index.borrow_mut();
+        if let Some(from) = from {
+            if let Some(ids) = index.get_mut(&from) {
+                ids.remove(&id);
+            }
+        }
+        if let Some(to) = to {
+            index.entry(to).or_default().insert(id);
+        }
+    });
+}
+
+pub fn now_seconds() -> i64 {
+    (ic_cdk::api::time() / NANOS_PER_SECOND) as i64
+}
+
+/// Reserves the id (nonce) of the next ticket.
+pub fn next_ticket_id() -> u64 {
+    TICKETS.with(|t| {
+        let mut state = t.borrow_mut();
+        let id = state.next_id;
+        state.next_id += 1;
+        id
+    })
+}
+
+pub fn insert_ticket(ticket: Ticket) {
+    let (id, status) = (ticket.id, ticket.status);
+    let replaced = TICKETS.with(|t| t.borrow_mut().tickets.insert(id, ticket));
+    index(id, replaced.map(|ticket| ticket.status), Some(status));
+}
+
+/// Records ticket `id`, reserved with `next_ticket_id`, for an amount already
+/// taken from `owner`, to be signed with the next batch.
+pub fn queue_ticket(
+    id: u64,
+    owner: Principal,
+    amount: u64,
+    recipient: String,
+    recipient_wallet: Option<String>,
+    wrapped_mint: Option<String>,
+) -> Ticket {
+    let ticket = Ticket {
+        id,
+        owner,
+        amount,
+        recipient,
+        recipient_wallet,
+        expiry: now_seconds() + crate::config::read_config(|c| c.ticket_ttl_seconds) as i64,
+        signature_hex: String::new(),
+        status: TicketStatus::Queued,
+        created_at: ic_cdk::api::time(),
+        batch_id: None,
+        merkle_proof: None,
+        wrapped_mint,
+    };
+    insert_ticket(ticket.clone());
+    ticket
+}
+
+pub fn get(id: u64) -> Option<Ticket> {
+    TICKETS.with(|t| t.borrow().tickets.get(&id).cloned())
+}
+
+pub fn set_status(id: u64, status: TicketStatus) {
+    let previous = TICKETS.with(|t| {
+        t.borrow_mut()
+            .tickets
+            .get_mut(&id)
+            .map(|ticket| std::mem::replace(&mut ticket.status, status))
+    });
+    if let Some(previous) = previous {
+        index(id, Some(previous), Some(status));
+    }
+}
+
+/// Ids of the tickets in `status`, oldest first.
+pub fn ids_with_status(status: TicketStatus) -> Vec<u64> {
+    BY_STATUS.with(|index| {
+        index
+            .borrow()
+            .get(&status)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// The oldest tickets waiting for a batch signature that have not expired yet.
+pub fn queued(limit: usize, now_seconds: i64) -> Vec<Ticket> {
+    ids_with_status(TicketStatus::Queued)
+        .into_iter()
+        .filter_map(get)
+        .filter(|ticket| !ticket.is_expired(now_seconds))
+        .take(limit)
+        .collect()
+}
+
+pub fn with_status(status: TicketStatus) -> Vec<Ticket> {
+    ids_with_status(status).into_iter().filter_map(get).collect()
+}
+
+/// Ids of the queued and signed tickets past their refund grace period, oldest first.
+pub fn refundable(now_seconds: i64) -> Vec<u64> {
+    let mut ids: Vec<u64> = [TicketStatus::Queued, TicketStatus::Signed]
+        .into_iter()
+        .flat_map(with_status)
+        .filter(|ticket| ticket.is_refundable(now_seconds))
+        .map(|ticket| ticket.id)
+        .collect();
+    ids.sort_unstable();
+    ids
+}
+
+/// Drops tickets settled more than `SETTLED_RETENTION_SECONDS` ago and returns
+/// the batches they belonged to. Redeemed pool-token tickets are added to
+/// `pruned_bridged_out` so reconciliation still accounts for them.
+pub fn prune_settled(now_seconds: i64) -> BTreeSet<u64> {
+    let prunable: Vec<u64> = [TicketStatus::Redeemed, TicketStatus::Refunded]
+        .into_iter()
+        .flat_map(with_status)
+        .filter(|ticket| ticket.is_prunable(now_seconds))
+        .map(|ticket| ticket.id)
+        .collect();
+    let mut batch_ids = BTreeSet::new();
+    TICKETS.with(|t| {
+        let mut state = t.borrow_mut();
+        for id in prunable {
+            let Some(ticket) = state.tickets.remove(&id) else {
+                continue;
+            };
+            if ticket.status == TicketStatus::Redeemed && ticket.wrapped_mint.is_none() {
+                let pruned = state.pruned_bridged_out.unwrap_or(0);
+                state.pruned_bridged_out = Some(pruned.saturating_add(ticket.amount));
+            }
+            batch_ids.extend(ticket.batch_id);
+            index(id, Some(ticket.status), None);
+        }
+    });
+    batch_ids
+}
+
+pub fn pruned_bridged_out() -> u64 {
+    TICKETS.with(|t| t.borrow().pruned_bridged_out.unwrap_or(0))
+}
+
+pub fn exists(id: u64) -> bool {
+    TICKETS.with(|t| t.borrow().tickets.contains_key(&id))
+}
+
+/// Only moves tickets that are still queued; one refunded while the batch was
+/// being signed stays refunded, its leaf has expired anyway.
+pub fn mark_batched(id: u64, batch_id: u64, signature_hex: &str, proof: Vec<String>) {
+    TICKETS.with(|t| {
+        if let Some(ticket) = t
+            .borrow_mut()
+            .tickets
+            .get_mut(&id)
+            .filter(|ticket| ticket.status == TicketStatus::Queued)
+        {
+            ticket.status = TicketStatus::Signed;
+            ticket.signature_hex = signature_hex.to_string();
+            ticket.batch_id = Some(batch_id);
+            ticket.merkle_proof = Some(proof);
+            index(id, Some(TicketStatus::Queued), Some(TicketStatus::Signed));
+        }
+    });
+}
+
+pub(crate) fn take_ticket_state() -> TicketState {
+    BY_STATUS.with(|index| index.borrow_mut().clear());
+    TICKETS.with(|t| t.take())
+}
+
+/// Restores the tickets and rebuilds the status index from them.
+pub(crate) fn restore_ticket_state(state: TicketState) {
+    BY_STATUS.with(|index| {
+        let mut index = index.borrow_mut();
+        index.clear();
+        for ticket in state.tickets.values() {
+            index.entry(ticket.status).or_default().insert(ticket.id);
+        }
+    });
+    TICKETS.with(|t| *t.borrow_mut() = state);
+}
+
+#[ic_cdk::query]
+fn get_ticket(id: u64) -> Option<Ticket> {
+    get(id)
+}
+
+#[ic_cdk::query]
+fn get_user_tickets(user: Principal) -> Vec<Ticket> {
+    TICKETS.with(|t| {
+        t.borrow()
+            .tickets
+            .values()
+            .filter(|ticket| ticket.owner == user)
+            .cloned()
+            .collect()
+    })
+}
@@ -0,0 +1,88 @@
+use crate::error::BridgeError;
+use crate::tickets;
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::call::call;
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// What the bridge owes the owner once the tokens of a transfer have moved.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Settlement {
+    /// A `deposit_to_pool`: credit the owner's bridge balance.
+    Credit { amount: u64 },
+    /// A wrapped token burn: queue the ticket named in the burn's memo.
+    QueueTicket {
+        id: u64,
+        amount: u64,
+        recipient: String,
+        recipient_wallet: Option<String>,
+        mint: String,
+    },
+}
+
+/// An `icrc2_transfer_from` into the canister, with a `created_at_time`, and
+/// what it pays for.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingTransfer {
+    pub ledger: Principal,
+    pub args: TransferFromArgs,
+    pub settlement: Settlement,
+}
+
+thread_local! {
+    // transfers whose reply was lost, at most one per owner thanks to `PrincipalGuard`
+    static PENDING: RefCell<BTreeMap<Principal, PendingTransfer>> = RefCell::new(BTreeMap::new());
+}
+
+pub(crate) fn take_pending_transfers() -> BTreeMap<Principal, PendingTransfer> {
+    PENDING.with(|p| p.take())
+}
+
+pub(crate) fn restore_pending_transfers(pending: BTreeMap<Principal, PendingTransfer>) {
+    PENDING.with(|p| *p.borrow_mut() = pending);
+}
+
+/// Pulls the tokens of `transfer` from `owner` and settles it. If the call
+/// fails the tokens may have moved anyway, so the transfer is kept and resent
+/// unchanged by `settle_pending`; the ledger then reports it as a duplicate
+/// instead of moving the tokens twice. The caller must hold the owner's `PrincipalGuard`.
+pub async fn transfer_from(owner: Principal, transfer: PendingTransfer) -> Result<u64, BridgeError> {
+    PENDING.with(|p| p.borrow_mut().insert(owner, transfer.clone()));
+    let (result,): (Result<Nat, TransferFromError>,) = call(transfer.ledger, "icrc2_transfer_from", (transfer.args,))
+        .await
+        .map_err(|err| BridgeError::call_failed("icrc2_transfer_from", err))?;
+    PENDING.with(|p| p.borrow_mut().remove(&owner));
+    let block_index = match result {
+        Ok(block_index) | Err(TransferFromError::Duplicate { duplicate_of: block_index }) => block_index,
+        Err(e) => return Err(BridgeError::LedgerError(e)),
+    };
+
+    match transfer.settlement {
+        Settlement::Credit { amount } => crate::credit_user(owner, amount),
+        Settlement::QueueTicket {
+            id,
+            amount,
+            recipient,
+            recipient_wallet,
+            mint,
+        } => {
+            tickets::queue_ticket(id, owner, amount, recipient, recipient_wallet, Some(mint));
+        }
+    }
+    Ok(block_index.0.to_u64_digits().first().copied().unwrap_or(0))
+}
+
+/// Resends the owner's transfer whose reply was lost, if any, before they
+/// start another one. Fails only if its outcome is still unknown.
+pub async fn settle_pending(owner: Principal) -> Result<(), BridgeError> {
+    let Some(pending) = PENDING.with(|p| p.borrow().get(&owner).cloned()) else {
+        return Ok(());
+    };
+    match transfer_from(owner, pending).await {
+        // rejected, e.g. as too old to deduplicate: resending it again cannot tell more
+        Ok(_) | Err(BridgeError::LedgerError(_)) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,332 @@
+use crate::config::read_config;
+use crate::error::BridgeError;
+use crate::guard::PrincipalGuard;
+use crate::roles::is_admin;
+use crate::solana_pda;
+use crate::tickets::{self, Ticket, TicketStatus};
+use crate::transfers::{self, PendingTransfer, Settlement};
+use base64::Engine;
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::call::call;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::{Memo, TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// An SPL mint and the ICRC ledger of its wrapped token, whose minting account
+/// is this canister's default account.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WrappedToken {
+    pub mint: String,
+    pub ledger_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct WrappedState {
+    /// SPL mint -> wrapped token ledger.
+    pub ledgers: BTreeMap<String, Principal>,
+    /// Solana `LockRecord` address -> block index of the mint. Never pruned:
+    /// lock records stay on Solana, so a dropped entry could be claimed again.
+    pub claimed_locks: BTreeMap<String, u64>,
+    /// Memo -> `created_at_time` of mints whose reply was lost. A retry sends
+    /// the same pair, so the ledger reports a duplicate instead of minting twice.
+    pub pending_mints: BTreeMap<Vec<u8>, u64>,
+}
+
+thread_local! {
+    static WRAPPED: RefCell<WrappedState> = RefCell::new(WrappedState::default());
+    static CLAIMING: RefCell<BTreeSet<String>> = RefCell::new(BTreeSet::new());
+}
+
+pub(crate) fn take_wrapped_state() -> WrappedState {
+    WRAPPED.with(|w| w.take())
+}
+
+pub(crate) fn restore_wrapped_state(state: WrappedState) {
+    WRAPPED.with(|w| *w.borrow_mut() = state);
+}
+
+fn ledger_for(mint: &str) -> Result<Principal, BridgeError> {
+    WRAPPED
+        .with(|w| w.borrow().ledgers.get(mint).copied())
+        .ok_or_else(|| BridgeError::NotFound(format!("No wrapped token is configured for mint {}", mint)))
+}
+
+/// A `LockRecord` account of the vault program, without its bump.
+struct LockRecord {
+    mint: [u8; 32],
+    amount: u64,
+    icp_recipient: Vec<u8>,
+}
+
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> Result<&'a [u8], String> {
+    if rest.len() < n {
+        return Err("Lock record is truncated".to_string());
+    }
+    let (head, tail) = rest.split_at(n);
+    *rest = tail;
+    Ok(head)
+}
+
+// Borsh layout after Anchor's 8-byte discriminator:
+// depositor (32), mint (32), amount (u64), icp_recipient (u32 length + bytes), locked_at (i64), bump (u8)
+fn decode_lock_record(data: &[u8]) -> Result<LockRecord, String> {
+    let discriminator = Sha256::digest(b"account:LockRecord");
+    if data.len() < 8 || data[..8] != discriminator[..8] {
+        return Err("Account is not a lock record".to_string());
+    }
+    let mut rest = &data[8..];
+    let _depositor = take(&mut rest, 32)?;
+    let mint: [u8; 32] = take(&mut rest, 32)?.try_into().unwrap();
+    let amount = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap());
+    let recipient_len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap()) as usize;
+    let icp_recipient = take(&mut rest, recipient_len)?.to_vec();
+    Ok(LockRecord {
+        mint,
+        amount,
+        icp_recipient,
+    })
+}
+
+// Marks a lock record as being claimed until dropped, so concurrent calls
+// cannot both pass the check while the mint is awaited.
+struct ClaimGuard(String);
+
+impl ClaimGuard {
+    fn new(lock_address: &str) -> Result<Self, BridgeError> {
+        if WRAPPED.with(|w| w.borrow().claimed_locks.contains_key(lock_address)) {
+            return Err(BridgeError::InvalidState(format!("Lock {} was already claimed", lock_address)));
+        }
+        if !CLAIMING.with(|c| c.borrow_mut().insert(lock_address.to_string())) {
+            return Err(BridgeError::RateLimited(format!("Lock {} is being claimed", lock_address)));
+        }
+        Ok(ClaimGuard(lock_address.to_string()))
+    }
+}
+
+impl Drop for ClaimGuard {
+    fn drop(&mut self) {
+        CLAIMING.with(|c| c.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Mints `amount` of the wrapped token of `mint` to `to`; a transfer from the
+/// minting account is a mint. `memo` identifies the mint: retrying one whose
+/// reply was lost, within the ledger's deduplication window, mints only once.
+pub async fn mint_wrapped(mint: &str, to: Principal, amount: u64, memo: Vec<u8>) -> Result<u64, BridgeError> {
+    let ledger = ledger_for(mint)?;
+    let created_at_time = WRAPPED.with(|w| {
+        *w.borrow_mut()
+            .pending_mints
+            .entry(memo.clone())
+            .or_insert_with(ic_cdk::api::time)
+    });
+    let transfer_args = TransferArg {
+        from_subaccount: None,
+        to: Account {
+            owner: to,
+            subaccount: None,
+        },
+        fee: None,
+        created_at_time: Some(created_at_time),
+        memo: Some(Memo::from(memo.clone())),
+        amount: Nat::from(amount),
+    };
+    // stays pending if the call fails: the mint may have happened anyway
+    let (result,): (Result<Nat, TransferError>,) = call(ledger, "icrc1_transfer", (transfer_args,))
+        .await
+        .map_err(|err| BridgeError::call_failed("icrc1_transfer", err))?;
+    WRAPPED.with(|w| w.borrow_mut().pending_mints.remove(&memo));
+    let block_index = match result {
+        Ok(block_index) | Err(TransferError::Duplicate { duplicate_of: block_index }) => block_index,
+        Err(e) => return Err(BridgeError::LedgerTransferError(e)),
+    };
+    Ok(block_index.0.to_u64_digits().first().copied().unwrap_or(0))
+}
+
+/// Mints the wrapped token for a finalized `lock` on Solana. Anyone can submit
+/// a lock; the tokens always go to the principal recorded in it.
+#[ic_cdk::update]
+async fn claim_solana_lock(lock_address: String) -> Result<u64, BridgeError> {
+    crate::check_not_paused()?;
+    let lock_bytes = solana_pda::decode_pubkey(&lock_address).map_err(BridgeError::InvalidAddress)?;
+    let _claim = ClaimGuard::new(&lock_address)?;
+
+    let (program_id, rpc) = read_config(|c| (c.solana_program_id.clone(), c.solana_rpc_client()));
+    let account = rpc
+        .get_account_info(&lock_address)
+        .await
+        .map_err(|e| BridgeError::SolanaRpcError(format!("Could not read the lock on Solana: {}", e)))?
+        .ok_or_else(|| BridgeError::NotFound(format!("Lock {} not found on Solana", lock_address)))?;
+    if account.owner != program_id {
+        return Err(BridgeError::InvalidAddress(format!(
+            "Account {} is not owned by the vault program",
+            lock_address
+        )));
+    }
+    let data = base64::engine::general_purpose::STANDARD
+        .decode(&account.data.0)
+        .map_err(|e| BridgeError::SolanaRpcError(format!("Invalid account data: {}", e)))?;
+    let record = decode_lock_record(&data).map_err(BridgeError::InvalidAddress)?;
+    let recipient = Principal::try_from_slice(&record.icp_recipient)
+        .map_err(|e| BridgeError::InvalidState(format!("Lock recipient is not a principal: {}", e)))?;
+
+    let mint = bs58::encode(record.mint).into_string();
+    let block_index = mint_wrapped(&mint, recipient, record.amount, lock_bytes.to_vec()).await?;
+    WRAPPED.with(|w| w.borrow_mut().claimed_locks.insert(lock_address, block_index));
+    Ok(block_index)
+}
+
+/// Burns `amount` of the caller's wrapped token (approved to this canister)
+/// and queues a ticket releasing the SPL tokens to the associated token
+/// account of `solana_wallet`. Wrapped SOL is released as native SOL to the
+/// wallet itself.
+#[ic_cdk::update]
+async fn bridge_wrapped_to_solana(
+    mint: String,
+    amount: u64,
+    solana_wallet: String,
+    from_subaccount: Option<Subaccount>,
+) -> Result<Ticket, BridgeError> {
+    crate::check_not_paused()?;
+    let caller = ic_cdk::caller();
+    let _guard = PrincipalGuard::new(caller)?;
+    if amount == 0 {
+        return Err(BridgeError::InvalidAmount("Amount must be greater than 0".to_string()));
+    }
+    let ledger = ledger_for(&mint)?;
+    let (recipient, recipient_wallet) = if mint == solana_pda::NATIVE_MINT {
+        // `withdraw_sol` pays the wallet named in the leaf
+        solana_pda::decode_pubkey(&solana_wallet).map_err(BridgeError::InvalidAddress)?;
+        (solana_wallet, None)
+    } else {
+        // `withdraw_batch` only pays token accounts of the leaf's mint, which the
+        // associated token account of the mint always is
+        let recipient =
+            solana_pda::associated_token_address(&solana_wallet, &mint).map_err(BridgeError::InvalidAddress)?;
+        (recipient, Some(solana_wallet))
+    };
+
+    transfers::settle_pending(caller).await?;
+
+    // a transfer to the minting account is a burn; its memo names the ticket it pays for
+    let id = tickets::next_ticket_id();
+    let transfer = PendingTransfer {
+        ledger,
+        args: TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: from_subaccount,
+            },
+            to: Account {
+                owner: ic_cdk::id(),
+                subaccount: None,
+            },
+            amount: amount.into(),
+            fee: None,
+            memo: Some(Memo::from(format!("ticket {}", id).into_bytes())),
+            created_at_time: Some(ic_cdk::api::time()),
+        },
+        settlement: Settlement::QueueTicket {
+            id,
+            amount,
+            recipient,
+            recipient_wallet,
+            mint,
+        },
+    };
+    transfers::transfer_from(caller, transfer).await?;
+    Ok(tickets::get(id).expect("the burn queued the ticket"))
+}
+
+#[ic_cdk::update(guard = "is_admin")]
+fn set_wrapped_ledger(mint: String, ledger_id: Principal) -> Result<(), BridgeError> {
+    solana_pda::decode_pubkey(&mint).map_err(BridgeError::InvalidAddress)?;
+    if ledger_id == Principal::anonymous() || ledger_id == Principal::management_canister() {
+        return Err(BridgeError::InvalidArgument(format!("{} is not a ledger canister", ledger_id)));
+    }
+    WRAPPED.with(|w| w.borrow_mut().ledgers.insert(mint, ledger_id));
+    Ok(())
+}
+
+/// Fails while tickets of `mint` are outstanding: refunding them mints on its ledger.
+#[ic_cdk::update(guard = "is_admin")]
+fn remove_wrapped_ledger(mint: String) -> Result<(), BridgeError> {
+    let outstanding = [TicketStatus::Queued, TicketStatus::Signed]
+        .into_iter()
+        .flat_map(tickets::with_status)
+        .filter(|ticket| ticket.wrapped_mint.as_deref() == Some(mint.as_str()))
+        .count();
+    if outstanding > 0 {
+        return Err(BridgeError::InvalidState(format!(
+            "{} tickets of mint {} are still queued or signed",
+            outstanding, mint
+        )));
+    }
+    WRAPPED.with(|w| w.borrow_mut().ledgers.remove(&mint));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn list_wrapped_ledgers() -> Vec<WrappedToken> {
+    WRAPPED.with(|w| {
+        w.borrow()
+            .ledgers
+            .iter()
+            .map(|(mint, ledger_id)| WrappedToken {
+                mint: mint.clone(),
+                ledger_id: *ledger_id,
+            })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn get_lock_claim(lock_address: String) -> Option<u64> {
+    WRAPPED.with(|w| w.borrow().claimed_locks.get(&lock_address).copied())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock_record_data(discriminator: &[u8], icp_recipient: &[u8]) -> Vec<u8> {
+        let mut data = discriminator[..8].to_vec();
+        data.extend_from_slice(&[3; 32]);
+        data.extend_from_slice(&[1; 32]);
+        data.extend_from_slice(&500u64.to_le_bytes());
+        data.extend_from_slice(&(icp_recipient.len() as u32).to_le_bytes());
+        data.extend_from_slice(icp_recipient);
+        data.extend_from_slice(&1_700_000_000i64.to_le_bytes());
+        data.push(254);
+        data
+    }
+
+    #[test]
+    fn decodes_a_lock_record() {
+        let data = lock_record_data(&Sha256::digest(b"account:LockRecord"), &[7; 10]);
+        let record = decode_lock_record(&data).unwrap();
+        assert_eq!(record.mint, [1; 32]);
+        assert_eq!(record.amount, 500);
+        assert_eq!(record.icp_recipient, vec![7; 10]);
+    }
+
+    #[test]
+    fn rejects_other_accounts() {
+        let data = lock_record_data(&Sha256::digest(b"account:Receipt"), &[7; 10]);
+        assert_eq!(decode_lock_record(&data).err().as_deref(), Some("Account is not a lock record"));
+        assert!(decode_lock_record(&[]).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_records() {
+        let data = lock_record_data(&Sha256::digest(b"account:LockRecord"), &[7; 10]);
+        // cut inside the principal, whose length prefix promises 10 bytes
+        let truncated = &data[..8 + 32 + 32 + 8 + 4 + 5];
+        assert_eq!(decode_lock_record(truncated).err().as_deref(), Some("Lock record is truncated"));
+    }
+}
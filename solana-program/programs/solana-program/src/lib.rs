@@ -1,18 +1,99 @@
 use anchor_lang::prelude::*;
-use anchor_spl::token::{transfer, Mint, Token, TokenAccount, Transfer};
+use anchor_lang::system_program;
+use anchor_lang::Discriminator;
+use anchor_spl::associated_token::{self, get_associated_token_address, AssociatedToken};
+use anchor_spl::token::spl_token::native_mint;
+use anchor_spl::token::{
+    close_account, sync_native, transfer, CloseAccount, Mint, SyncNative, Token, TokenAccount, Transfer,
+};
 use solana_program::instruction::Instruction;
 use solana_program::sysvar::instructions::{load_instruction_at_checked, ID as IX_ID};
 pub mod utils;
-use crate::utils::{hex_to_array, hex_to_array_64};
+use crate::utils::{batch_message, verify_ed25519, verify_merkle_proof, withdrawal_leaf, NATIVE_SOL};
 
 declare_id!("JAd7tStmfYcnhhc1Sh2CX8n99Hssk4rDVTHwu7KmHQ9S");
 
-const ICPPUB: &str = "6d2bb964c9a0523f2477986ef79bc352139562032f656aee809b1a83d49d512e";
 #[program]
 pub mod vault {
-    use utils::verify_ed25519;
     use super::*;
 
+    /// Creates the singleton config. Only the program's upgrade authority may
+    /// call it; `admin` can be any key, including a multisig (e.g. Squads vault) PDA.
+    pub fn initialize_config(
+        ctx: Context<InitializeConfig>,
+        admin: Pubkey,
+        signers: Vec<[u8; 32]>,
+        threshold: u8,
+    ) -> Result<()> {
+        Config::validate_signers(&signers, threshold)?;
+        ctx.accounts.config.set_inner(Config {
+            admin,
+            pending_admin: None,
+            signers,
+            threshold,
+            previous_signers: vec![],
+            previous_threshold: 0,
+            previous_valid_until: 0,
+            paused: false,
+            bump: ctx.bumps.config,
+        });
+        Ok(())
+    }
+
+    /// First step of an admin transfer; takes effect once `new_admin` calls `accept_admin`.
+    pub fn propose_admin(ctx: Context<AdminOnly>, new_admin: Pubkey) -> Result<()> {
+        msg!("proposing {} as admin", new_admin);
+        ctx.accounts.config.pending_admin = Some(new_admin);
+        Ok(())
+    }
+
+    pub fn accept_admin(ctx: Context<AcceptAdmin>) -> Result<()> {
+        let config = &mut ctx.accounts.config;
+        config.admin = ctx.accounts.pending_admin.key();
+        config.pending_admin = None;
+        msg!("{} accepted the admin role", config.admin);
+        Ok(())
+    }
+
+    pub fn set_paused(ctx: Context<AdminOnly>, paused: bool) -> Result<()> {
+        ctx.accounts.config.paused = paused;
+        Ok(())
+    }
+
+    /// Replaces the set of Ed25519 keys that authorise withdrawals and how many of them must sign.
+    /// Signatures from the replaced keys stop being accepted immediately, e.g. after a compromise.
+    pub fn set_signers(ctx: Context<AdminOnly>, signers: Vec<[u8; 32]>, threshold: u8) -> Result<()> {
+        Config::validate_signers(&signers, threshold)?;
+        let config = &mut ctx.accounts.config;
+        config.signers = signers;
+        config.threshold = threshold;
+        config.previous_signers = vec![];
+        config.previous_threshold = 0;
+        config.previous_valid_until = 0;
+        Ok(())
+    }
+
+    /// Installs a new signer set while the current one stays accepted until
+    /// `previous_valid_until` (Unix timestamp), so signatures issued before a
+    /// key rotation remain redeemable during the overlap window.
+    pub fn rotate_signers(
+        ctx: Context<AdminOnly>,
+        signers: Vec<[u8; 32]>,
+        threshold: u8,
+        previous_valid_until: i64,
+    ) -> Result<()> {
+        Config::validate_signers(&signers, threshold)?;
+        if previous_valid_until <= Clock::get()?.unix_timestamp {
+            return err!(ErrorCode::InvalidRotationWindow);
+        }
+        let config = &mut ctx.accounts.config;
+        config.previous_signers = std::mem::replace(&mut config.signers, signers);
+        config.previous_threshold = std::mem::replace(&mut config.threshold, threshold);
+        config.previous_valid_until = previous_valid_until;
+        msg!("signers rotated, previous set valid until {}", previous_valid_until);
+        Ok(())
+    }
+
     pub fn initialize_vault(ctx: Context<InitializeVault>, deposit_amount: u64) -> Result<()> {
         // ensure deposit amount is greater than 0
         if deposit_amount <= 0 {
@@ -48,6 +129,7 @@ pub mod vault {
             owner: ctx.accounts.owner.key(),
             mint: ctx.accounts.mint.key(),
             bumps,
+            version: Vault::VERSION,
         });
         Ok(())
     }
@@ -76,56 +158,727 @@ pub mod vault {
         Ok(())
     }
 
-    pub fn withdraw(ctx: Context<Withdraw>, withdraw_amount: u64, sig: String) -> Result<()> {
-        let vault_token_balance = &ctx.accounts.vault_token_account.amount;
-        if vault_token_balance < &withdraw_amount || withdraw_amount <= 0 {
-            return err!(ErrorCode::InvalidWithdrawAmount);
+    /// Lets `vault` pay out bridge tickets and take locks. Anyone can create a
+    /// vault, so only the ones the admin registers back the bridge.
+    pub fn register_vault(ctx: Context<RegisterVault>) -> Result<()> {
+        let vault = ctx.accounts.vault.key();
+        ctx.accounts.bridge_vault.set_inner(BridgeVault {
+            vault,
+            paused: false,
+            bump: ctx.bumps.bridge_vault,
+        });
+        msg!("vault {} registered", vault);
+        Ok(())
+    }
+
+    /// Stops `vault` from paying out tickets; signatures still outstanding for it
+    /// fail and are refunded on ICP.
+    pub fn unregister_vault(ctx: Context<UnregisterVault>) -> Result<()> {
+        msg!("vault {} unregistered", ctx.accounts.bridge_vault.vault);
+        // the registration is closed by its `close = rent_receiver` constraint
+        Ok(())
+    }
+
+    /// Stops or resumes payouts and locks for one registered vault, e.g. once
+    /// an insolvency found by `verify_solvency` has been investigated.
+    pub fn set_vault_paused(ctx: Context<SetVaultPaused>, paused: bool) -> Result<()> {
+        msg!("vault {} paused: {}", ctx.accounts.bridge_vault.vault, paused);
+        ctx.accounts.bridge_vault.paused = paused;
+        Ok(())
+    }
+
+    /// Locks tokens in the vault to be minted as the wrapped token on ICP. The
+    /// canister reads the `LockRecord` and mints to `icp_recipient` (principal
+    /// bytes); `lock_id` only has to be unique per depositor.
+    pub fn lock(ctx: Context<LockTokens>, lock_id: u64, amount: u64, icp_recipient: Vec<u8>) -> Result<()> {
+        if amount == 0 {
+            return err!(ErrorCode::InvalidDepositAmount);
         }
+        if icp_recipient.is_empty() || icp_recipient.len() > LockRecord::MAX_PRINCIPAL_LEN {
+            return err!(ErrorCode::InvalidIcpRecipient);
+        }
+
+        msg!("locking {} for ICP, lock id {}", amount, lock_id);
+        transfer(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                Transfer {
+                    from: ctx.accounts.depositor_token_account.to_account_info(),
+                    to: ctx.accounts.vault_token_account.to_account_info(),
+                    authority: ctx.accounts.depositor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        // locked tokens back the wrapped supply, so they count as vault liabilities
+        let vault_data = &mut ctx.accounts.vault;
+        vault_data.deposited_amount = vault_data.deposited_amount.checked_add(amount).unwrap();
+
+        ctx.accounts.lock_record.set_inner(LockRecord {
+            depositor: ctx.accounts.depositor.key(),
+            mint: vault_data.mint,
+            amount,
+            icp_recipient,
+            locked_at: Clock::get()?.unix_timestamp,
+            bump: ctx.bumps.lock_record,
+        });
+        Ok(())
+    }
+
+    /// Same as `lock` for native SOL: the lamports are moved into the vault's wSOL
+    /// token account and synced, and the record names the native mint, whose
+    /// wrapped token on ICP is wrapped SOL.
+    pub fn lock_sol(ctx: Context<LockSol>, lock_id: u64, amount: u64, icp_recipient: Vec<u8>) -> Result<()> {
+        if amount == 0 {
+            return err!(ErrorCode::InvalidDepositAmount);
+        }
+        if icp_recipient.is_empty() || icp_recipient.len() > LockRecord::MAX_PRINCIPAL_LEN {
+            return err!(ErrorCode::InvalidIcpRecipient);
+        }
+
+        msg!("locking {} lamports for ICP, lock id {}", amount, lock_id);
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.depositor.to_account_info(),
+                    to: ctx.accounts.vault_token_account.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+        sync_native(CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            SyncNative {
+                account: ctx.accounts.vault_token_account.to_account_info(),
+            },
+        ))?;
 
-        let message = format!(
-            "{}_{}",
+        let vault_data = &mut ctx.accounts.vault;
+        vault_data.deposited_amount = vault_data.deposited_amount.checked_add(amount).unwrap();
+
+        ctx.accounts.lock_record.set_inner(LockRecord {
+            depositor: ctx.accounts.depositor.key(),
+            mint: native_mint::ID,
+            amount,
+            icp_recipient,
+            locked_at: Clock::get()?.unix_timestamp,
+            bump: ctx.bumps.lock_record,
+        });
+        Ok(())
+    }
+
+    /// Releases tokens authorised by the ICP canister. The signed message binds
+    /// the ticket nonce, amount, recipient token account and expiry.
+    pub fn withdraw(
+        ctx: Context<Withdraw>,
+        withdraw_amount: u64,
+        nonce: u64,
+        expiry: i64,
+        signatures: Vec<BridgeSignature>,
+    ) -> Result<()> {
+        let recipient = ctx.accounts.recipient_token_account.key();
+        ctx.accounts.config.authorize_withdrawal(
+            ctx.accounts.vault_token_account.amount,
             withdraw_amount,
-            &ctx.accounts.owner_token_account.key()
-        );
-        msg!("message is {} ", message);
+            nonce,
+            &recipient,
+            expiry,
+            &signatures,
+        )?;
+
+        msg!("Withdrawing {} to recipient account", withdraw_amount);
+        release_from_vault(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_authority,
+            &mut ctx.accounts.vault_token_account,
+            ctx.accounts.recipient_token_account.to_account_info(),
+            withdraw_amount,
+        )?;
+
+        // The receipt marks the nonce as consumed; its existence is what the
+        // canister checks before refunding an expired ticket.
+        ctx.accounts.receipt.set_inner(Receipt {
+            nonce,
+            amount: withdraw_amount,
+            recipient,
+            redeemed_at: Clock::get()?.unix_timestamp,
+            bump: ctx.bumps.receipt,
+        });
+        Ok(())
+    }
 
-        let pub_bytes = hex_to_array(ICPPUB);
+    /// Same as `withdraw`, but takes the recipient wallet and pays into its
+    /// associated token account for the vault mint, creating it first if needed.
+    /// The signed message still names the associated token account address.
+    /// The payer covers the rent of a created account.
+    pub fn withdraw_to_wallet(
+        ctx: Context<WithdrawToWallet>,
+        withdraw_amount: u64,
+        nonce: u64,
+        expiry: i64,
+        signatures: Vec<BridgeSignature>,
+    ) -> Result<()> {
+        let recipient = ctx.accounts.recipient_token_account.key();
+        ctx.accounts.config.authorize_withdrawal(
+            ctx.accounts.vault_token_account.amount,
+            withdraw_amount,
+            nonce,
+            &recipient,
+            expiry,
+            &signatures,
+        )?;
+
+        if ctx.accounts.recipient_token_account.data_is_empty() {
+            msg!("creating associated token account {}", recipient);
+            associated_token::create(CpiContext::new(
+                ctx.accounts.associated_token_program.to_account_info(),
+                associated_token::Create {
+                    payer: ctx.accounts.payer.to_account_info(),
+                    associated_token: ctx.accounts.recipient_token_account.to_account_info(),
+                    authority: ctx.accounts.recipient.to_account_info(),
+                    mint: ctx.accounts.mint.to_account_info(),
+                    system_program: ctx.accounts.system_program.to_account_info(),
+                    token_program: ctx.accounts.token_program.to_account_info(),
+                },
+            ))?;
+        }
+
+        msg!("Withdrawing {} to recipient account", withdraw_amount);
+        release_from_vault(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_authority,
+            &mut ctx.accounts.vault_token_account,
+            ctx.accounts.recipient_token_account.to_account_info(),
+            withdraw_amount,
+        )?;
+
+        ctx.accounts.receipt.set_inner(Receipt {
+            nonce,
+            amount: withdraw_amount,
+            recipient,
+            redeemed_at: Clock::get()?.unix_timestamp,
+            bump: ctx.bumps.receipt,
+        });
+        Ok(())
+    }
 
-        if pub_bytes.len() != 32 {
-            return err!(ErrorCode::InvalidICPPubKey);
+    /// Settles several withdrawals authorised by one set of bridge signatures over
+    /// the Merkle root of a batch. `remaining_accounts` holds, for every entry of
+    /// `withdrawals` in order, the recipient token account and its receipt PDA,
+    /// both writable, followed by the recipient wallet for entries with
+    /// `create_recipient_account` set. For those the recipient is the wallet's
+    /// associated token account, created at the payer's expense if missing.
+    pub fn withdraw_batch<'info>(
+        ctx: Context<'_, '_, 'info, 'info, WithdrawBatch<'info>>,
+        root: [u8; 32],
+        signatures: Vec<BridgeSignature>,
+        withdrawals: Vec<BatchWithdrawal>,
+    ) -> Result<()> {
+        let account_count: usize = withdrawals.iter().map(BatchWithdrawal::account_count).sum();
+        if withdrawals.is_empty() || ctx.remaining_accounts.len() != account_count {
+            return err!(ErrorCode::InvalidBatchAccounts);
         }
-        let pub_array: [u8; 32] = pub_bytes.try_into().unwrap();
+        ctx.accounts
+            .config
+            .verify_signatures(batch_message(&root).as_bytes(), &signatures)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        let mint = ctx.accounts.vault.mint;
+        let mut accounts = ctx.remaining_accounts.iter();
+        for withdrawal in &withdrawals {
+            let (recipient_info, receipt_info) = match (accounts.next(), accounts.next()) {
+                (Some(recipient), Some(receipt)) => (recipient, receipt),
+                _ => return err!(ErrorCode::InvalidBatchAccounts),
+            };
+            if withdrawal.amount == 0 || withdrawal.amount > ctx.accounts.vault_token_account.amount {
+                return err!(ErrorCode::InvalidWithdrawAmount);
+            }
+            if now > withdrawal.expiry {
+                return err!(ErrorCode::SignatureExpired);
+            }
+            if withdrawal.create_recipient_account {
+                let wallet_info = accounts.next().ok_or(ErrorCode::InvalidBatchAccounts)?;
+                ctx.accounts.create_recipient_account(recipient_info, wallet_info)?;
+            }
 
-        verify_ed25519(pub_array, message.as_bytes().to_vec(), hex_to_array_64(&sig))?;
+            let recipient_token_account = Account::<TokenAccount>::try_from(recipient_info)?;
+            if recipient_token_account.mint != mint || !recipient_info.is_writable {
+                return err!(ErrorCode::InvalidBatchAccounts);
+            }
+            let leaf = withdrawal_leaf(
+                withdrawal.nonce,
+                withdrawal.amount,
+                &mint,
+                recipient_info.key,
+                withdrawal.expiry,
+            );
+            if !verify_merkle_proof(leaf, &withdrawal.proof, &root) {
+                return err!(ErrorCode::InvalidMerkleProof);
+            }
+
+            ctx.accounts.create_receipt(
+                receipt_info,
+                ctx.program_id,
+                Receipt {
+                    nonce: withdrawal.nonce,
+                    amount: withdrawal.amount,
+                    recipient: recipient_info.key(),
+                    redeemed_at: now,
+                    bump: 0,
+                },
+            )?;
+
+            msg!("Withdrawing {} for nonce {}", withdrawal.amount, withdrawal.nonce);
+            release_from_vault(
+                &ctx.accounts.token_program,
+                &mut ctx.accounts.vault,
+                &ctx.accounts.vault_authority,
+                &mut ctx.accounts.vault_token_account,
+                recipient_info.clone(),
+                withdrawal.amount,
+            )?;
+        }
+        Ok(())
+    }
 
-        msg!("Withdrawing {} to owner account", withdraw_amount);
+    /// Checks the vault's invariants and returns its books. Anyone may call it
+    /// for a registered vault; if the token account holds less than the recorded
+    /// liabilities that vault is paused until the admin investigates.
+    pub fn verify_solvency(ctx: Context<VerifySolvency>) -> Result<SolvencyReport> {
+        let vault = &ctx.accounts.vault;
+        let token_balance = ctx.accounts.vault_token_account.amount;
+        let liabilities = vault.deposited_amount.checked_sub(vault.withdrawn_amount);
+        let solvent = matches!(liabilities, Some(owed) if token_balance >= owed);
+        if !solvent {
+            msg!(
+                "vault {} is insolvent: deposited {}, withdrawn {}, balance {}; pausing it",
+                vault.key(),
+                vault.deposited_amount,
+                vault.withdrawn_amount,
+                token_balance
+            );
+            ctx.accounts.bridge_vault.paused = true;
+        }
+        Ok(SolvencyReport {
+            deposited_amount: vault.deposited_amount,
+            withdrawn_amount: vault.withdrawn_amount,
+            token_balance,
+            solvent,
+        })
+    }
 
-        let release_to_owner = Transfer {
-            from: ctx.accounts.vault_token_account.to_account_info(),
-            to: ctx.accounts.owner_token_account.to_account_info(),
-            authority: ctx.accounts.vault_authority.to_account_info(),
+    /// Rewrites a vault created by an older program version in the current
+    /// layout, growing the account if needed. The payer covers any extra rent.
+    pub fn migrate_vault(ctx: Context<MigrateVault>) -> Result<()> {
+        let info = ctx.accounts.vault.to_account_info();
+        let Some(migrated) = Vault::migrate_data(&info.try_borrow_data()?)? else {
+            msg!("vault is already at version {}", Vault::VERSION);
+            return Ok(());
         };
-        transfer(
-            ctx.accounts
-                .token_program_context(release_to_owner)
-                .with_signer(&[&[
-                    b"authority",
-                    ctx.accounts.vault.key().as_ref(),
-                    &[ctx.accounts.vault.bumps.vault_authority],
-                ]]),
-            withdraw_amount,
+
+        let rent = Rent::get()?.minimum_balance(Vault::LEN);
+        let top_up = rent.saturating_sub(info.lamports());
+        if top_up > 0 {
+            system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    system_program::Transfer {
+                        from: ctx.accounts.payer.to_account_info(),
+                        to: info.clone(),
+                    },
+                ),
+                top_up,
+            )?;
+        }
+        if info.data_len() != Vault::LEN {
+            info.realloc(Vault::LEN, true)?;
+        }
+
+        let mut data = info.try_borrow_mut_data()?;
+        let mut writer: &mut [u8] = &mut data;
+        migrated.try_serialize(&mut writer)?;
+        msg!("vault migrated to version {}", Vault::VERSION);
+        Ok(())
+    }
+
+    /// Adds SOL to the vault of the native mint: the lamports are moved into the
+    /// vault's wSOL token account and synced, so the owner does not have to wrap them.
+    pub fn deposit_sol(ctx: Context<DepositSol>, deposit_amount: u64) -> Result<()> {
+        if deposit_amount == 0 {
+            return err!(ErrorCode::InvalidDepositAmount);
+        }
+
+        msg!("depositing {} lamports to vault", deposit_amount);
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.owner.to_account_info(),
+                    to: ctx.accounts.vault_token_account.to_account_info(),
+                },
+            ),
+            deposit_amount,
         )?;
+        sync_native(CpiContext::new(
+            ctx.accounts.token_program.to_account_info(),
+            SyncNative {
+                account: ctx.accounts.vault_token_account.to_account_info(),
+            },
+        ))?;
 
         let vault_data = &mut ctx.accounts.vault;
-        let updated_withdrawn_amount = vault_data
-            .withdrawn_amount
-            .checked_add(withdraw_amount)
-            .unwrap();
-        vault_data.withdrawn_amount = updated_withdrawn_amount;
+        vault_data.deposited_amount = vault_data.deposited_amount.checked_add(deposit_amount).unwrap();
+        Ok(())
+    }
+
+    /// Pays a SOL ticket of a signed batch, one whose leaf has `NATIVE_SOL` in
+    /// place of the mint, to the recipient wallet named in it. The wSOL is unwrapped through a temporary token account
+    /// whose rent the payer gets back in the same instruction.
+    pub fn withdraw_sol(
+        ctx: Context<WithdrawSol>,
+        withdraw_amount: u64,
+        nonce: u64,
+        expiry: i64,
+        root: [u8; 32],
+        proof: Vec<[u8; 32]>,
+        signatures: Vec<BridgeSignature>,
+    ) -> Result<()> {
+        let recipient = ctx.accounts.recipient.key();
+        if withdraw_amount == 0 || withdraw_amount > ctx.accounts.vault_token_account.amount {
+            return err!(ErrorCode::InvalidWithdrawAmount);
+        }
+        if Clock::get()?.unix_timestamp > expiry {
+            return err!(ErrorCode::SignatureExpired);
+        }
+        ctx.accounts
+            .config
+            .verify_signatures(batch_message(&root).as_bytes(), &signatures)?;
+        let leaf = withdrawal_leaf(nonce, withdraw_amount, &NATIVE_SOL, &recipient, expiry);
+        if !verify_merkle_proof(leaf, &proof, &root) {
+            return err!(ErrorCode::InvalidMerkleProof);
+        }
+
+        msg!("Withdrawing {} lamports to {}", withdraw_amount, recipient);
+        release_from_vault(
+            &ctx.accounts.token_program,
+            &mut ctx.accounts.vault,
+            &ctx.accounts.vault_authority,
+            &mut ctx.accounts.vault_token_account,
+            ctx.accounts.unwrap_account.to_account_info(),
+            withdraw_amount,
+        )?;
+
+        // closing hands the payer the unwrapped lamports plus the rent it put up,
+        // the withdrawn amount is then passed on to the recipient
+        let vault_key = ctx.accounts.vault.key();
+        close_account(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                CloseAccount {
+                    account: ctx.accounts.unwrap_account.to_account_info(),
+                    destination: ctx.accounts.payer.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+            )
+            .with_signer(&[&[
+                b"authority",
+                vault_key.as_ref(),
+                &[ctx.accounts.vault.bumps.vault_authority],
+            ]]),
+        )?;
+        system_program::transfer(
+            CpiContext::new(
+                ctx.accounts.system_program.to_account_info(),
+                system_program::Transfer {
+                    from: ctx.accounts.payer.to_account_info(),
+                    to: ctx.accounts.recipient.to_account_info(),
+                },
+            ),
+            withdraw_amount,
+        )?;
+
+        ctx.accounts.receipt.set_inner(Receipt {
+            nonce,
+            amount: withdraw_amount,
+            recipient,
+            redeemed_at: Clock::get()?.unix_timestamp,
+            bump: ctx.bumps.receipt,
+        });
+        Ok(())
+    }
+
+    /// Closes a vault that owes nothing, i.e. everything deposited was withdrawn,
+    /// and returns the rent of both accounts to the owner. Tokens sent to the
+    /// vault outside of `deposit` go to the owner's token account first.
+    /// Signatures still outstanding for this vault fail and are refunded on ICP.
+    pub fn close_vault(ctx: Context<CloseVault>) -> Result<()> {
+        let vault_key = ctx.accounts.vault.key();
+        let signer_seeds: &[&[&[u8]]] = &[&[
+            b"authority",
+            vault_key.as_ref(),
+            &[ctx.accounts.vault.bumps.vault_authority],
+        ]];
+
+        let surplus = ctx.accounts.vault_token_account.amount;
+        if surplus > 0 {
+            msg!("returning {} surplus tokens to the owner", surplus);
+            transfer(
+                CpiContext::new(
+                    ctx.accounts.token_program.to_account_info(),
+                    Transfer {
+                        from: ctx.accounts.vault_token_account.to_account_info(),
+                        to: ctx.accounts.owner_token_account.to_account_info(),
+                        authority: ctx.accounts.vault_authority.to_account_info(),
+                    },
+                )
+                .with_signer(signer_seeds),
+                surplus,
+            )?;
+        }
+
+        close_account(
+            CpiContext::new(
+                ctx.accounts.token_program.to_account_info(),
+                CloseAccount {
+                    account: ctx.accounts.vault_token_account.to_account_info(),
+                    destination: ctx.accounts.owner.to_account_info(),
+                    authority: ctx.accounts.vault_authority.to_account_info(),
+                },
+            )
+            .with_signer(signer_seeds),
+        )?;
+        msg!("vault {} closed", vault_key);
+        // the vault account itself is closed by its `close = owner` constraint
+        Ok(())
+    }
+}
+
+/// Transfers `amount` out of the vault token account, signed by the vault authority PDA.
+/// The token account is reloaded afterwards, so the next release in the same
+/// instruction checks solvency against the balance actually left.
+fn release_from_vault<'info>(
+    token_program: &Program<'info, Token>,
+    vault: &mut Account<'info, Vault>,
+    vault_authority: &SystemAccount<'info>,
+    vault_token_account: &mut Account<'info, TokenAccount>,
+    recipient_token_account: AccountInfo<'info>,
+    amount: u64,
+) -> Result<()> {
+    // never pay out more than was deposited, nor from a vault already short of its liabilities
+    let liabilities = vault.check_solvency(vault_token_account.amount)?;
+    if amount > liabilities {
+        return err!(ErrorCode::InsolventVault);
+    }
+
+    let release_to_recipient = Transfer {
+        from: vault_token_account.to_account_info(),
+        to: recipient_token_account,
+        authority: vault_authority.to_account_info(),
+    };
+    transfer(
+        CpiContext::new(token_program.to_account_info(), release_to_recipient).with_signer(&[&[
+            b"authority",
+            vault.key().as_ref(),
+            &[vault.bumps.vault_authority],
+        ]]),
+        amount,
+    )?;
+    vault_token_account.reload()?;
+
+    vault.withdrawn_amount = vault.withdrawn_amount.checked_add(amount).unwrap();
+    Ok(())
+}
+
+/// A signature from one of the configured bridge signers; relayers decode the
+/// hex signature returned by the ICP canister.
+#[derive(AnchorDeserialize, AnchorSerialize, Debug, Clone)]
+pub struct BridgeSignature {
+    pub signer: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+/// One leaf of a signed batch, see `utils::withdrawal_leaf`.
+#[derive(AnchorDeserialize, AnchorSerialize, Debug, Clone)]
+pub struct BatchWithdrawal {
+    pub nonce: u64,
+    pub amount: u64,
+    pub expiry: i64,
+    pub proof: Vec<[u8; 32]>,
+    /// The recipient is the associated token account of a wallet passed after
+    /// the receipt, and is created if it does not exist yet.
+    pub create_recipient_account: bool,
+}
+
+impl BatchWithdrawal {
+    /// Number of `remaining_accounts` this entry takes in `withdraw_batch`.
+    fn account_count(&self) -> usize {
+        if self.create_recipient_account {
+            3
+        } else {
+            2
+        }
+    }
+}
+
+#[account]
+#[derive(Debug)]
+pub struct Config {
+    pub admin: Pubkey,
+    pub pending_admin: Option<Pubkey>,
+    pub signers: Vec<[u8; 32]>,
+    pub threshold: u8,
+    // signer set replaced by the last rotation, accepted until `previous_valid_until`
+    pub previous_signers: Vec<[u8; 32]>,
+    pub previous_threshold: u8,
+    pub previous_valid_until: i64,
+    pub paused: bool,
+    pub bump: u8,
+}
+
+impl Config {
+    pub const MAX_SIGNERS: usize = 10;
+
+    pub const LEN: usize = {
+        let discriminator = 8;
+        let admin = 32;
+        let pending_admin = 1 + 32;
+        let signers = 4 + Self::MAX_SIGNERS * 32;
+        let threshold = 1;
+        let previous_signers = 4 + Self::MAX_SIGNERS * 32;
+        let previous_threshold = 1;
+        let previous_valid_until = 8;
+        let paused = 1;
+        let bump = 1;
+        discriminator
+            + admin
+            + pending_admin
+            + signers
+            + threshold
+            + previous_signers
+            + previous_threshold
+            + previous_valid_until
+            + paused
+            + bump
+    };
+
+    pub fn validate_signers(signers: &[[u8; 32]], threshold: u8) -> Result<()> {
+        if signers.is_empty() || signers.len() > Self::MAX_SIGNERS {
+            return err!(ErrorCode::InvalidSignerSet);
+        }
+        if threshold == 0 || threshold as usize > signers.len() {
+            return err!(ErrorCode::InvalidThreshold);
+        }
+        for (i, signer) in signers.iter().enumerate() {
+            if signers[..i].contains(signer) {
+                return err!(ErrorCode::InvalidSignerSet);
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the amount and expiry of a withdrawal and the bridge signatures over
+    /// `nonce_amount_recipient_expiry`.
+    pub fn authorize_withdrawal(
+        &self,
+        vault_token_balance: u64,
+        withdraw_amount: u64,
+        nonce: u64,
+        recipient: &Pubkey,
+        expiry: i64,
+        signatures: &[BridgeSignature],
+    ) -> Result<()> {
+        if vault_token_balance < withdraw_amount || withdraw_amount == 0 {
+            return err!(ErrorCode::InvalidWithdrawAmount);
+        }
+        if Clock::get()?.unix_timestamp > expiry {
+            return err!(ErrorCode::SignatureExpired);
+        }
+
+        let message = format!("{}_{}_{}_{}", nonce, withdraw_amount, recipient, expiry);
+        msg!("message is {} ", message);
+        self.verify_signatures(message.as_bytes(), signatures)
+    }
+
+    /// Requires valid signatures over `message` from at least `threshold` distinct
+    /// configured signers, or from the previous signer set while its overlap window is open.
+    pub fn verify_signatures(&self, message: &[u8], signatures: &[BridgeSignature]) -> Result<()> {
+        let current = Self::verify_signer_set(&self.signers, self.threshold, message, signatures);
+        if current.is_err()
+            && !self.previous_signers.is_empty()
+            && Clock::get()?.unix_timestamp <= self.previous_valid_until
+        {
+            return Self::verify_signer_set(
+                &self.previous_signers,
+                self.previous_threshold,
+                message,
+                signatures,
+            );
+        }
+        current
+    }
+
+    // Signatures from keys outside `signers` are skipped rather than rejected:
+    // during a rotation overlap one submission may mix old and new signers, and
+    // only those of the set being checked count towards its threshold.
+    fn verify_signer_set(
+        signers: &[[u8; 32]],
+        threshold: u8,
+        message: &[u8],
+        signatures: &[BridgeSignature],
+    ) -> Result<()> {
+        let mut verified: Vec<[u8; 32]> = Vec::with_capacity(signatures.len());
+        for signature in signatures.iter().filter(|s| signers.contains(&s.signer)) {
+            if verified.contains(&signature.signer) {
+                return err!(ErrorCode::DuplicateSigner);
+            }
+            verify_ed25519(signature.signer, message.to_vec(), signature.signature)?;
+            verified.push(signature.signer);
+        }
+        if verified.len() < threshold as usize {
+            return err!(ErrorCode::InsufficientSignatures);
+        }
         Ok(())
     }
 }
 
+#[derive(Accounts)]
+pub struct InitializeConfig<'info> {
+    #[account(mut)]
+    authority: Signer<'info>,
+    #[account(
+        init,
+        payer = authority,
+        space = Config::LEN,
+        seeds = [b"config".as_ref()], bump
+    )]
+    config: Account<'info, Config>,
+    #[account(constraint = program.programdata_address()? == Some(program_data.key()))]
+    program: Program<'info, crate::program::Vault>,
+    #[account(constraint = program_data.upgrade_authority_address == Some(authority.key()) @ ErrorCode::Unauthorized)]
+    program_data: Account<'info, ProgramData>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct AdminOnly<'info> {
+    #[account(address = config.admin @ ErrorCode::Unauthorized)]
+    admin: Signer<'info>,
+    #[account(mut, seeds = [b"config".as_ref()], bump = config.bump)]
+    config: Account<'info, Config>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptAdmin<'info> {
+    #[account(constraint = config.pending_admin == Some(pending_admin.key()) @ ErrorCode::Unauthorized)]
+    pending_admin: Signer<'info>,
+    #[account(mut, seeds = [b"config".as_ref()], bump = config.bump)]
+    config: Account<'info, Config>,
+}
+
 #[derive(Accounts)]
 pub struct InitializeVault<'info> {
     // external accounts
@@ -187,47 +940,99 @@ pub struct Vault {
     pub owner: Pubkey,
     pub mint: Pubkey,
     pub bumps: Bumps,
+    /// Layout version, `Vault::VERSION` for vaults the program can use; older
+    /// vaults have to go through `migrate_vault` first.
+    pub version: u8,
 }
 
 impl Vault {
+    pub const VERSION: u8 = 1;
+
+    /// Tokens the vault still owes, i.e. `deposited_amount - withdrawn_amount`,
+    /// provided the token account holds at least that much.
+    pub fn check_solvency(&self, token_balance: u64) -> Result<u64> {
+        let liabilities = self
+            .deposited_amount
+            .checked_sub(self.withdrawn_amount)
+            .ok_or(ErrorCode::InsolventVault)?;
+        if token_balance < liabilities {
+            return err!(ErrorCode::InsolventVault);
+        }
+        Ok(liabilities)
+    }
+
+    /// Decodes the data of a vault account written by an older program
+    /// version; `None` if it already is in the current layout.
+    fn migrate_data(data: &[u8]) -> Result<Option<Vault>> {
+        if data.len() < 8 || data[..8] != Vault::DISCRIMINATOR {
+            return err!(ErrorCode::UnsupportedVaultVersion);
+        }
+        match data.get(VaultV0::VERSION_OFFSET).copied().unwrap_or(0) {
+            Vault::VERSION => Ok(None),
+            0 => Ok(Some(VaultV0::deserialize(&mut &data[8..])?.migrate())),
+            _ => err!(ErrorCode::UnsupportedVaultVersion),
+        }
+    }
+
     pub const LEN: usize = {
         let discriminator = 8;
         let amounts = 2 * 8;
-        let option = 1;
         let initialized = 1;
         let pubkeys = 2 * 32;
         let vault_bumps = 3 * 1;
-        discriminator + amounts + option + initialized + pubkeys + vault_bumps
+        let version = 1;
+        discriminator + amounts + initialized + pubkeys + vault_bumps + version
     };
 }
 
+/// Layout of vaults created before `Vault::version` existed. Their `LEN` had
+/// one byte of zeroed padding, which is where `version` now sits, so these
+/// read as version 0.
+#[derive(AnchorDeserialize)]
+struct VaultV0 {
+    deposited_amount: u64,
+    withdrawn_amount: u64,
+    initialized: bool,
+    owner: Pubkey,
+    mint: Pubkey,
+    bumps: Bumps,
+}
+
+impl VaultV0 {
+    const VERSION_OFFSET: usize = 8 + 2 * 8 + 1 + 2 * 32 + 3;
+
+    fn migrate(self) -> Vault {
+        Vault {
+            deposited_amount: self.deposited_amount,
+            withdrawn_amount: self.withdrawn_amount,
+            initialized: self.initialized,
+            owner: self.owner,
+            mint: self.mint,
+            bumps: self.bumps,
+            version: Vault::VERSION,
+        }
+    }
+}
+
 #[derive(Accounts)]
-pub struct Deposit<'info> {
-    // External accounts
-    #[account(address = vault.owner)]
+pub struct DepositSol<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, constraint = !config.paused @ ErrorCode::BridgePaused)]
+    config: Account<'info, Config>,
+
+    #[account(mut, address = vault.owner)]
     owner: Signer<'info>,
-    #[account(mut, token::mint=vault.mint, token::authority=owner)]
-    owner_token_account: Account<'info, TokenAccount>,
-    #[account(constraint = mint.is_initialized == true)]
-    mint: Account<'info, Mint>,
 
     // PDAs
     #[account(
         mut,
-        seeds = [b"vault".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        seeds = [b"vault".as_ref(), owner.key().as_ref(), native_mint::ID.as_ref()],
         bump = vault.bumps.vault,
         constraint = vault.initialized == true,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
     )]
     vault: Account<'info, Vault>,
-    #[account(
-        seeds = [b"authority".as_ref(), vault.key().as_ref()],
-        bump = vault.bumps.vault_authority
-    )]
-    vault_authority: SystemAccount<'info>,
     #[account(
         mut,
-        token::mint=vault.mint,
-        token::authority=vault_authority,
         seeds = [b"tokens".as_ref(), vault.key().as_ref()],
         bump = vault.bumps.vault_token_account
     )]
@@ -235,35 +1040,39 @@ pub struct Deposit<'info> {
 
     // Programs section
     token_program: Program<'info, Token>,
-}
-
-impl<'info> Deposit<'info> {
-    fn token_program_context<T: ToAccountMetas + ToAccountInfos<'info>>(
-        &self,
-        data: T,
-    ) -> CpiContext<'_, '_, '_, 'info, T> {
-        CpiContext::new(self.token_program.to_account_info(), data)
-    }
+    system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct Withdraw<'info> {
+#[instruction(withdraw_amount: u64, nonce: u64)]
+pub struct WithdrawSol<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, constraint = !config.paused @ ErrorCode::BridgePaused)]
+    config: Account<'info, Config>,
+
     // External accounts
-    #[account(address = vault.owner)]
-    owner: Signer<'info>,
-    #[account(mut, token::mint=vault.mint, token::authority=owner)]
-    owner_token_account: Account<'info, TokenAccount>,
-    #[account(constraint = mint.is_initialized == true)]
+    #[account(mut)]
+    payer: Signer<'info>,
+    /// Wallet named in the signed leaf; a system account, so a ticket for a token account cannot be paid in SOL.
+    #[account(mut)]
+    recipient: SystemAccount<'info>,
+    #[account(address = native_mint::ID)]
     mint: Account<'info, Mint>,
 
     // PDAs
     #[account(
         mut,
-        seeds = [b"vault".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), mint.key().as_ref()],
         bump = vault.bumps.vault,
         constraint = vault.initialized == true,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
     )]
     vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"bridge_vault".as_ref(), vault.key().as_ref()],
+        bump = bridge_vault.bump,
+        constraint = !bridge_vault.paused @ ErrorCode::VaultPaused
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
     #[account(
         seeds = [b"authority".as_ref(), vault.key().as_ref()],
         bump = vault.bumps.vault_authority
@@ -277,23 +1086,653 @@ pub struct Withdraw<'info> {
         bump = vault.bumps.vault_token_account
     )]
     vault_token_account: Account<'info, TokenAccount>,
+    /// Created and closed within `withdraw_sol`.
+    #[account(
+        init,
+        payer = payer,
+        token::mint=mint,
+        token::authority=vault_authority,
+        seeds = [b"unwrap".as_ref(), vault.key().as_ref()], bump
+    )]
+    unwrap_account: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = Receipt::LEN,
+        seeds = [b"receipt".as_ref(), nonce.to_le_bytes().as_ref()], bump
+    )]
+    receipt: Account<'info, Receipt>,
 
     // Programs section
     token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
 }
 
-impl<'info> Withdraw<'info> {
-    fn token_program_context<T: ToAccountMetas + ToAccountInfos<'info>>(
-        &self,
-        data: T,
-    ) -> CpiContext<'_, '_, '_, 'info, T> {
-        CpiContext::new(self.token_program.to_account_info(), data)
-    }
-}
+#[derive(Accounts)]
+pub struct CloseVault<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump)]
+    config: Account<'info, Config>,
 
-#[error_code]
-pub enum ErrorCode {
-    #[msg("Deposit amount must be greater than 0")]
+    #[account(constraint = authority.key() == vault.owner || authority.key() == config.admin @ ErrorCode::Unauthorized)]
+    authority: Signer<'info>,
+    /// CHECK: only receives lamports; must be the vault owner.
+    #[account(mut, address = vault.owner)]
+    owner: UncheckedAccount<'info>,
+    #[account(mut, token::mint=vault.mint, token::authority=vault.owner)]
+    owner_token_account: Account<'info, TokenAccount>,
+
+    // PDAs
+    #[account(
+        mut,
+        close = owner,
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), vault.mint.as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+        constraint = vault.deposited_amount == vault.withdrawn_amount @ ErrorCode::OutstandingLiabilities,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"authority".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_authority
+    )]
+    vault_authority: SystemAccount<'info>,
+    #[account(
+        mut,
+        seeds = [b"tokens".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_token_account
+    )]
+    vault_token_account: Account<'info, TokenAccount>,
+
+    // Programs section
+    token_program: Program<'info, Token>,
+}
+
+#[derive(Accounts)]
+pub struct MigrateVault<'info> {
+    #[account(mut)]
+    payer: Signer<'info>,
+    /// CHECK: may still be in a layout `Account<Vault>` cannot read; the
+    /// discriminator and version are checked by the instruction.
+    #[account(mut, owner = crate::ID)]
+    vault: UncheckedAccount<'info>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Deposit<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, constraint = !config.paused @ ErrorCode::BridgePaused)]
+    config: Account<'info, Config>,
+
+    // External accounts
+    #[account(address = vault.owner)]
+    owner: Signer<'info>,
+    #[account(mut, token::mint=vault.mint, token::authority=owner)]
+    owner_token_account: Account<'info, TokenAccount>,
+    #[account(constraint = mint.is_initialized == true)]
+    mint: Account<'info, Mint>,
+
+    // PDAs
+    #[account(
+        mut,
+        seeds = [b"vault".as_ref(), owner.key().as_ref(), mint.key().as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.initialized == true,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"authority".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_authority
+    )]
+    vault_authority: SystemAccount<'info>,
+    #[account(
+        mut,
+        token::mint=vault.mint,
+        token::authority=vault_authority,
+        seeds = [b"tokens".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_token_account
+    )]
+    vault_token_account: Account<'info, TokenAccount>,
+
+    // Programs section
+    token_program: Program<'info, Token>,
+}
+
+impl<'info> Deposit<'info> {
+    fn token_program_context<T: ToAccountMetas + ToAccountInfos<'info>>(
+        &self,
+        data: T,
+    ) -> CpiContext<'_, '_, '_, 'info, T> {
+        CpiContext::new(self.token_program.to_account_info(), data)
+    }
+}
+
+#[derive(AnchorDeserialize, AnchorSerialize, Debug, Clone)]
+pub struct SolvencyReport {
+    pub deposited_amount: u64,
+    pub withdrawn_amount: u64,
+    pub token_balance: u64,
+    pub solvent: bool,
+}
+
+#[derive(Accounts)]
+pub struct VerifySolvency<'info> {
+    #[account(
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), vault.mint.as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"tokens".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_token_account
+    )]
+    vault_token_account: Account<'info, TokenAccount>,
+    #[account(
+        mut,
+        seeds = [b"bridge_vault".as_ref(), vault.key().as_ref()],
+        bump = bridge_vault.bump
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+}
+
+/// Registration of a vault that backs the bridge, see `register_vault`.
+#[account]
+#[derive(Debug)]
+pub struct BridgeVault {
+    pub vault: Pubkey,
+    // set by `verify_solvency` on a deficit and cleared by the admin
+    pub paused: bool,
+    pub bump: u8,
+}
+
+impl BridgeVault {
+    pub const LEN: usize = 8 + 32 + 1 + 1;
+}
+
+#[derive(Accounts)]
+pub struct RegisterVault<'info> {
+    #[account(address = config.admin @ ErrorCode::Unauthorized)]
+    admin: Signer<'info>,
+    #[account(mut)]
+    payer: Signer<'info>,
+    #[account(seeds = [b"config".as_ref()], bump = config.bump)]
+    config: Account<'info, Config>,
+    #[account(
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), vault.mint.as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        init,
+        payer = payer,
+        space = BridgeVault::LEN,
+        seeds = [b"bridge_vault".as_ref(), vault.key().as_ref()], bump
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct UnregisterVault<'info> {
+    #[account(address = config.admin @ ErrorCode::Unauthorized)]
+    admin: Signer<'info>,
+    /// CHECK: only receives the rent of the registration.
+    #[account(mut)]
+    rent_receiver: UncheckedAccount<'info>,
+    #[account(seeds = [b"config".as_ref()], bump = config.bump)]
+    config: Account<'info, Config>,
+    #[account(
+        mut,
+        close = rent_receiver,
+        seeds = [b"bridge_vault".as_ref(), bridge_vault.vault.as_ref()],
+        bump = bridge_vault.bump
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+}
+
+#[derive(Accounts)]
+pub struct SetVaultPaused<'info> {
+    #[account(address = config.admin @ ErrorCode::Unauthorized)]
+    admin: Signer<'info>,
+    #[account(seeds = [b"config".as_ref()], bump = config.bump)]
+    config: Account<'info, Config>,
+    #[account(
+        mut,
+        seeds = [b"bridge_vault".as_ref(), bridge_vault.vault.as_ref()],
+        bump = bridge_vault.bump
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+}
+
+/// Created by `lock` and `lock_sol`; the ICP canister mints the wrapped token once per record.
+#[account]
+#[derive(Debug)]
+pub struct LockRecord {
+    pub depositor: Pubkey,
+    pub mint: Pubkey,
+    pub amount: u64,
+    pub icp_recipient: Vec<u8>,
+    pub locked_at: i64,
+    pub bump: u8,
+}
+
+impl LockRecord {
+    pub const MAX_PRINCIPAL_LEN: usize = 29;
+
+    pub const LEN: usize = {
+        let discriminator = 8;
+        let depositor = 32;
+        let mint = 32;
+        let amount = 8;
+        let icp_recipient = 4 + Self::MAX_PRINCIPAL_LEN;
+        let locked_at = 8;
+        let bump = 1;
+        discriminator + depositor + mint + amount + icp_recipient + locked_at + bump
+    };
+}
+
+#[derive(Accounts)]
+#[instruction(lock_id: u64)]
+pub struct LockTokens<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, constraint = !config.paused @ ErrorCode::BridgePaused)]
+    config: Account<'info, Config>,
+
+    // External accounts
+    #[account(mut)]
+    depositor: Signer<'info>,
+    #[account(mut, token::mint=vault.mint, token::authority=depositor)]
+    depositor_token_account: Account<'info, TokenAccount>,
+    #[account(constraint = mint.is_initialized == true)]
+    mint: Account<'info, Mint>,
+
+    // PDAs
+    #[account(
+        mut,
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), mint.key().as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.initialized == true,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"bridge_vault".as_ref(), vault.key().as_ref()],
+        bump = bridge_vault.bump,
+        constraint = !bridge_vault.paused @ ErrorCode::VaultPaused
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+    #[account(
+        mut,
+        seeds = [b"tokens".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_token_account
+    )]
+    vault_token_account: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = depositor,
+        space = LockRecord::LEN,
+        seeds = [b"lock".as_ref(), depositor.key().as_ref(), lock_id.to_le_bytes().as_ref()], bump
+    )]
+    lock_record: Account<'info, LockRecord>,
+
+    // Programs section
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(lock_id: u64)]
+pub struct LockSol<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, constraint = !config.paused @ ErrorCode::BridgePaused)]
+    config: Account<'info, Config>,
+
+    // External accounts
+    #[account(mut)]
+    depositor: Signer<'info>,
+
+    // PDAs
+    #[account(
+        mut,
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), native_mint::ID.as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.initialized == true,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"bridge_vault".as_ref(), vault.key().as_ref()],
+        bump = bridge_vault.bump,
+        constraint = !bridge_vault.paused @ ErrorCode::VaultPaused
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+    #[account(
+        mut,
+        seeds = [b"tokens".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_token_account
+    )]
+    vault_token_account: Account<'info, TokenAccount>,
+    #[account(
+        init,
+        payer = depositor,
+        space = LockRecord::LEN,
+        seeds = [b"lock".as_ref(), depositor.key().as_ref(), lock_id.to_le_bytes().as_ref()], bump
+    )]
+    lock_record: Account<'info, LockRecord>,
+
+    // Programs section
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+/// Created by `withdraw` for every redeemed ticket; it makes nonces single-use.
+#[account]
+#[derive(Debug)]
+pub struct Receipt {
+    pub nonce: u64,
+    pub amount: u64,
+    pub recipient: Pubkey,
+    pub redeemed_at: i64,
+    pub bump: u8,
+}
+
+impl Receipt {
+    pub const LEN: usize = {
+        let discriminator = 8;
+        let nonce = 8;
+        let amount = 8;
+        let recipient = 32;
+        let redeemed_at = 8;
+        let bump = 1;
+        discriminator + nonce + amount + recipient + redeemed_at + bump
+    };
+}
+
+#[derive(Accounts)]
+#[instruction(withdraw_amount: u64, nonce: u64)]
+pub struct Withdraw<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, constraint = !config.paused @ ErrorCode::BridgePaused)]
+    config: Account<'info, Config>,
+
+    // External accounts
+    /// Anyone may relay a signed withdrawal; the payer covers fees and the receipt rent.
+    #[account(mut)]
+    payer: Signer<'info>,
+    /// Token account named in the signed message, owned by whoever should receive the funds.
+    #[account(mut, token::mint=vault.mint)]
+    recipient_token_account: Account<'info, TokenAccount>,
+    #[account(constraint = mint.is_initialized == true)]
+    mint: Account<'info, Mint>,
+
+    // PDAs
+    #[account(
+        mut,
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), mint.key().as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.initialized == true,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"bridge_vault".as_ref(), vault.key().as_ref()],
+        bump = bridge_vault.bump,
+        constraint = !bridge_vault.paused @ ErrorCode::VaultPaused
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+    #[account(
+        seeds = [b"authority".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_authority
+    )]
+    vault_authority: SystemAccount<'info>,
+    #[account(
+        mut,
+        token::mint=vault.mint,
+        token::authority=vault_authority,
+        seeds = [b"tokens".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_token_account
+    )]
+    vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = Receipt::LEN,
+        seeds = [b"receipt".as_ref(), nonce.to_le_bytes().as_ref()], bump
+    )]
+    receipt: Account<'info, Receipt>,
+
+    // Programs section
+    token_program: Program<'info, Token>,
+    system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawBatch<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, constraint = !config.paused @ ErrorCode::BridgePaused)]
+    config: Account<'info, Config>,
+
+    /// Relayer submitting the batch; pays fees and the rent of every receipt.
+    #[account(mut)]
+    payer: Signer<'info>,
+    #[account(constraint = mint.is_initialized == true)]
+    mint: Account<'info, Mint>,
+
+    // PDAs
+    #[account(
+        mut,
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), mint.key().as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.initialized == true,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"bridge_vault".as_ref(), vault.key().as_ref()],
+        bump = bridge_vault.bump,
+        constraint = !bridge_vault.paused @ ErrorCode::VaultPaused
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+    #[account(
+        seeds = [b"authority".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_authority
+    )]
+    vault_authority: SystemAccount<'info>,
+    #[account(
+        mut,
+        token::mint=vault.mint,
+        token::authority=vault_authority,
+        seeds = [b"tokens".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_token_account
+    )]
+    vault_token_account: Account<'info, TokenAccount>,
+
+    // Programs section
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    system_program: Program<'info, System>,
+}
+
+impl<'info> WithdrawBatch<'info> {
+    // Same account `init` creates in `withdraw`, done by hand because the
+    // receipts come from `remaining_accounts`. Creating it fails if the nonce
+    // was already redeemed, including twice within one batch.
+    fn create_receipt(
+        &self,
+        receipt_info: &AccountInfo<'info>,
+        program_id: &Pubkey,
+        mut receipt: Receipt,
+    ) -> Result<()> {
+        let nonce_bytes = receipt.nonce.to_le_bytes();
+        let (address, bump) = Pubkey::find_program_address(&[b"receipt", &nonce_bytes], program_id);
+        if receipt_info.key() != address || !receipt_info.is_writable {
+            return err!(ErrorCode::InvalidBatchAccounts);
+        }
+        if !receipt_info.data_is_empty() || receipt_info.owner == program_id {
+            return err!(ErrorCode::NonceAlreadyUsed);
+        }
+
+        // Anyone can send lamports to the receipt address before it is created,
+        // so a funded account is not a redeemed nonce. Like Anchor's `init`, top
+        // it up to the rent minimum and allocate it instead of failing.
+        let signer_seeds: &[&[u8]] = &[b"receipt", &nonce_bytes, &[bump]];
+        let required = Rent::get()?.minimum_balance(Receipt::LEN);
+        let funded = receipt_info.lamports();
+        if funded == 0 {
+            system_program::create_account(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    system_program::CreateAccount {
+                        from: self.payer.to_account_info(),
+                        to: receipt_info.clone(),
+                    },
+                )
+                .with_signer(&[signer_seeds]),
+                required,
+                Receipt::LEN as u64,
+                program_id,
+            )?;
+        } else {
+            let missing = required.saturating_sub(funded);
+            if missing > 0 {
+                system_program::transfer(
+                    CpiContext::new(
+                        self.system_program.to_account_info(),
+                        system_program::Transfer {
+                            from: self.payer.to_account_info(),
+                            to: receipt_info.clone(),
+                        },
+                    ),
+                    missing,
+                )?;
+            }
+            system_program::allocate(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    system_program::Allocate {
+                        account_to_allocate: receipt_info.clone(),
+                    },
+                )
+                .with_signer(&[signer_seeds]),
+                Receipt::LEN as u64,
+            )?;
+            system_program::assign(
+                CpiContext::new(
+                    self.system_program.to_account_info(),
+                    system_program::Assign {
+                        account_to_assign: receipt_info.clone(),
+                    },
+                )
+                .with_signer(&[signer_seeds]),
+                program_id,
+            )?;
+        }
+
+        receipt.bump = bump;
+        let mut data = receipt_info.try_borrow_mut_data()?;
+        let mut writer: &mut [u8] = &mut data;
+        receipt.try_serialize(&mut writer)
+    }
+}
+
+impl<'info> WithdrawBatch<'info> {
+    // The wallet comes from `remaining_accounts` unchecked; deriving the
+    // recipient from it means the account is either empty or a valid token
+    // account of the vault mint.
+    fn create_recipient_account(
+        &self,
+        recipient_info: &AccountInfo<'info>,
+        wallet_info: &AccountInfo<'info>,
+    ) -> Result<()> {
+        if recipient_info.key() != get_associated_token_address(wallet_info.key, &self.vault.mint) {
+            return err!(ErrorCode::InvalidRecipientAccount);
+        }
+        if !recipient_info.data_is_empty() {
+            return Ok(());
+        }
+
+        msg!("creating associated token account {}", recipient_info.key());
+        associated_token::create(CpiContext::new(
+            self.associated_token_program.to_account_info(),
+            associated_token::Create {
+                payer: self.payer.to_account_info(),
+                associated_token: recipient_info.clone(),
+                authority: wallet_info.clone(),
+                mint: self.mint.to_account_info(),
+                system_program: self.system_program.to_account_info(),
+                token_program: self.token_program.to_account_info(),
+            },
+        ))
+    }
+}
+
+#[derive(Accounts)]
+#[instruction(withdraw_amount: u64, nonce: u64)]
+pub struct WithdrawToWallet<'info> {
+    #[account(seeds = [b"config".as_ref()], bump = config.bump, constraint = !config.paused @ ErrorCode::BridgePaused)]
+    config: Account<'info, Config>,
+
+    // External accounts
+    #[account(mut)]
+    payer: Signer<'info>,
+    /// CHECK: any wallet; only used as the owner of the associated token account.
+    recipient: UncheckedAccount<'info>,
+    /// CHECK: the recipient's associated token account for the vault mint. The
+    /// address is derived here, so it is either empty or a valid token account.
+    #[account(
+        mut,
+        address = get_associated_token_address(&recipient.key(), &vault.mint) @ ErrorCode::InvalidRecipientAccount
+    )]
+    recipient_token_account: UncheckedAccount<'info>,
+    #[account(constraint = mint.is_initialized == true)]
+    mint: Account<'info, Mint>,
+
+    // PDAs
+    #[account(
+        mut,
+        seeds = [b"vault".as_ref(), vault.owner.as_ref(), mint.key().as_ref()],
+        bump = vault.bumps.vault,
+        constraint = vault.initialized == true,
+        constraint = vault.version == Vault::VERSION @ ErrorCode::UnsupportedVaultVersion,
+    )]
+    vault: Account<'info, Vault>,
+    #[account(
+        seeds = [b"bridge_vault".as_ref(), vault.key().as_ref()],
+        bump = bridge_vault.bump,
+        constraint = !bridge_vault.paused @ ErrorCode::VaultPaused
+    )]
+    bridge_vault: Account<'info, BridgeVault>,
+    #[account(
+        seeds = [b"authority".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_authority
+    )]
+    vault_authority: SystemAccount<'info>,
+    #[account(
+        mut,
+        token::mint=vault.mint,
+        token::authority=vault_authority,
+        seeds = [b"tokens".as_ref(), vault.key().as_ref()],
+        bump = vault.bumps.vault_token_account
+    )]
+    vault_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = Receipt::LEN,
+        seeds = [b"receipt".as_ref(), nonce.to_le_bytes().as_ref()], bump
+    )]
+    receipt: Account<'info, Receipt>,
+
+    // Programs section
+    token_program: Program<'info, Token>,
+    associated_token_program: Program<'info, AssociatedToken>,
+    system_program: Program<'info, System>,
+}
+
+#[error_code]
+pub enum ErrorCode {
+    #[msg("Deposit amount must be greater than 0")]
     InvalidDepositAmount,
 
     #[msg("Withdraw amount must be an amount available in the vault")]
@@ -304,4 +1743,112 @@ pub enum ErrorCode {
 
     #[msg("Failed to convert Vec<u8> to array")]
     FailToConvert,
+
+    #[msg("Signer is not authorized for this instruction")]
+    Unauthorized,
+
+    #[msg("Bridge is paused")]
+    BridgePaused,
+
+    #[msg("Vault is paused")]
+    VaultPaused,
+
+    #[msg("Signer set must be non-empty, unique and at most Config::MAX_SIGNERS keys")]
+    InvalidSignerSet,
+
+    #[msg("Threshold must be between 1 and the number of signers")]
+    InvalidThreshold,
+
+    #[msg("More than one signature from the same signer")]
+    DuplicateSigner,
+
+    #[msg("Not enough valid signatures to reach the threshold")]
+    InsufficientSignatures,
+
+    #[msg("Overlap window for the previous signers must end in the future")]
+    InvalidRotationWindow,
+
+    #[msg("Bridge signature has expired")]
+    SignatureExpired,
+
+    #[msg("Recipient token account is not the associated token account of the recipient")]
+    InvalidRecipientAccount,
+
+    #[msg("Withdrawal is not part of the signed batch")]
+    InvalidMerkleProof,
+
+    #[msg("Batch needs a writable recipient token account and receipt per withdrawal")]
+    InvalidBatchAccounts,
+
+    #[msg("Nonce was already redeemed")]
+    NonceAlreadyUsed,
+
+    #[msg("Vault holds less than its recorded liabilities")]
+    InsolventVault,
+
+    #[msg("Vault account layout version is not supported, run migrate_vault")]
+    UnsupportedVaultVersion,
+
+    #[msg("Vault still owes deposited tokens")]
+    OutstandingLiabilities,
+
+    #[msg("ICP recipient must be a principal of 1 to 29 bytes")]
+    InvalidIcpRecipient,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A vault as written before `version` existed: the V0 fields followed by
+    // the zeroed padding byte of its `LEN`.
+    fn v0_vault_data() -> Vec<u8> {
+        let mut data = Vault::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&5_000u64.to_le_bytes());
+        data.extend_from_slice(&1_200u64.to_le_bytes());
+        data.push(1);
+        data.extend_from_slice(&[3; 32]);
+        data.extend_from_slice(&[4; 32]);
+        data.extend_from_slice(&[253, 254, 255]);
+        data.push(0);
+        data
+    }
+
+    #[test]
+    fn migrates_a_v0_vault() {
+        let data = v0_vault_data();
+        assert_eq!(data.len(), Vault::LEN);
+
+        let vault = Vault::migrate_data(&data).unwrap().expect("a V0 vault needs migrating");
+        assert_eq!(vault.deposited_amount, 5_000);
+        assert_eq!(vault.withdrawn_amount, 1_200);
+        assert!(vault.initialized);
+        assert_eq!(vault.owner, Pubkey::new_from_array([3; 32]));
+        assert_eq!(vault.mint, Pubkey::new_from_array([4; 32]));
+        assert_eq!(
+            (vault.bumps.vault, vault.bumps.vault_authority, vault.bumps.vault_token_account),
+            (253, 254, 255)
+        );
+        assert_eq!(vault.version, Vault::VERSION);
+
+        // the migrated vault is written in place and reads back as current
+        let mut migrated = Vec::new();
+        vault.try_serialize(&mut migrated).unwrap();
+        assert_eq!(migrated.len(), Vault::LEN);
+        assert_eq!(migrated[VaultV0::VERSION_OFFSET], Vault::VERSION);
+        assert_eq!(migrated[..VaultV0::VERSION_OFFSET], data[..VaultV0::VERSION_OFFSET]);
+        assert!(Vault::migrate_data(&migrated).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_vault_data() {
+        let mut data = v0_vault_data();
+        data[VaultV0::VERSION_OFFSET] = Vault::VERSION + 1;
+        assert!(Vault::migrate_data(&data).is_err());
+
+        let mut data = v0_vault_data();
+        data[0] ^= 1;
+        assert!(Vault::migrate_data(&data).is_err());
+        assert!(Vault::migrate_data(&[]).is_err());
+    }
 }
@@ -0,0 +1,78 @@
+use anchor_lang::prelude::*;
+use solana_program::hash::hashv;
+
+// Domain separation between leaves and inner nodes, so an inner node can
+// never be passed off as a withdrawal.
+const LEAF_PREFIX: &[u8] = &[0];
+const NODE_PREFIX: &[u8] = &[1];
+
+/// Hashed in place of the mint for tickets paid in native SOL by `withdraw_sol`.
+/// No mint has this address, so SOL tickets never verify in `withdraw_batch`,
+/// and token tickets, wSOL included, never in `withdraw_sol`.
+pub const NATIVE_SOL: Pubkey = Pubkey::new_from_array([0; 32]);
+
+/// Hash of one withdrawal in a batch signed by the bridge; the ICP canister
+/// builds its tree from exactly the same bytes. Callers pass the mint of the
+/// paying vault, so a ticket only verifies against a vault of its own token.
+pub fn withdrawal_leaf(nonce: u64, amount: u64, mint: &Pubkey, recipient: &Pubkey, expiry: i64) -> [u8; 32] {
+    hashv(&[
+        LEAF_PREFIX,
+        &nonce.to_le_bytes(),
+        &amount.to_le_bytes(),
+        mint.as_ref(),
+        recipient.as_ref(),
+        &expiry.to_le_bytes(),
+    ])
+    .to_bytes()
+}
+
+/// Pairs are hashed in sorted order, so proofs carry no left/right flags.
+fn hash_pair(a: &[u8; 32], b: &[u8; 32]) -> [u8; 32] {
+    let (first, second) = if a <= b { (a, b) } else { (b, a) };
+    hashv(&[NODE_PREFIX, first, second]).to_bytes()
+}
+
+pub fn verify_merkle_proof(leaf: [u8; 32], proof: &[[u8; 32]], root: &[u8; 32]) -> bool {
+    let computed = proof.iter().fold(leaf, |node, sibling| hash_pair(&node, sibling));
+    &computed == root
+}
+
+/// The message the bridge signs to authorise every withdrawal under `root`.
+pub fn batch_message(root: &[u8; 32]) -> String {
+    let root_hex: String = root.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("root_{}", root_hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Same vector as the canister's `merkle` tests: three leaves with nonces
+    // 0..3, amounts 1000, 2000 and 3000, mint [1; 32] and recipient [2; 32].
+    #[test]
+    fn matches_the_vector_shared_with_icp() {
+        let leaves: Vec<[u8; 32]> = (0..3u64)
+            .map(|nonce| {
+                withdrawal_leaf(
+                    nonce,
+                    1_000 * (nonce + 1),
+                    &Pubkey::new_from_array([1; 32]),
+                    &Pubkey::new_from_array([2; 32]),
+                    1_700_000_000,
+                )
+            })
+            .collect();
+        let leaf_hex: String = leaves[0].iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(leaf_hex, "b2c867a8c3c5bfcdb5f2449974e697358392be9a2918ae3b749a3b9d61d89993");
+
+        let first_pair = hash_pair(&leaves[0], &leaves[1]);
+        let root = hash_pair(&first_pair, &leaves[2]);
+        assert_eq!(
+            batch_message(&root),
+            "root_7f2d5e58e4b5b058dab367be3bc08ef4d8d01aa62f83ec97424296a07a412ad3"
+        );
+        assert!(verify_merkle_proof(leaves[0], &[leaves[1], leaves[2]], &root));
+        assert!(verify_merkle_proof(leaves[2], &[first_pair], &root));
+        assert!(!verify_merkle_proof(leaves[1], &[leaves[1], leaves[2]], &root));
+    }
+}
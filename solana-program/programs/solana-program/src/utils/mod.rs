@@ -1,2 +1,4 @@
 pub mod ed25519;
+pub mod merkle;
 pub use ed25519::*;
+pub use merkle::*;
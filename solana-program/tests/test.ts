@@ -1,134 +1,247 @@
-import * as anchor from "@project-serum/anchor";
-import { Program, AnchorProvider, Idl, web3 } from "@project-serum/anchor";
+import * as anchor from "@coral-xyz/anchor";
+import { AnchorError, Program, AnchorProvider } from "@coral-xyz/anchor";
 import { Vault } from "../target/types/vault"; // 确保这个类型已生成
-import vaultIdlJson from "../target/idl/vault.json"; // IDL JSON 文件路径
-import { PublicKey, SystemProgram } from "@solana/web3.js";
+import { PublicKey, SystemProgram, SYSVAR_RENT_PUBKEY } from "@solana/web3.js";
 import { TOKEN_PROGRAM_ID, createMint, createAccount, mintTo, getAccount } from "@solana/spl-token";
+import { ed25519 } from "@noble/curves/ed25519";
 import assert from "assert";
 
+const BPF_LOADER_UPGRADEABLE_ID = new PublicKey("BPFLoaderUpgradeab1e11111111111111111111111");
+
+// Fails unless `promise` is rejected with the program error `code`.
+async function expectError(promise: Promise<unknown>, code: string) {
+  let error: unknown;
+  try {
+    await promise;
+  } catch (err) {
+    error = err;
+  }
+  assert.ok(error instanceof AnchorError, `expected ${code}, got ${error}`);
+  assert.strictEqual(error.error.errorCode.code, code);
+}
+
 describe("Vault Program", () => {
   // 初始化 provider 和 program
   const provider = AnchorProvider.env();
   anchor.setProvider(provider);
+  const program = anchor.workspace.Vault as Program<Vault>;
+  const payer = (provider.wallet as anchor.Wallet).payer;
 
-  const vaultIdl = vaultIdlJson as unknown as Idl;
-  const programId = new PublicKey(vaultIdl.metadata.address); // 使用 IDL 中的地址
-  const program = new Program<Vault>(vaultIdl, programId, provider);  // 使用类型Program<Vault>
+  // Stands in for the ICP canister's threshold key, the only configured signer.
+  const bridgeSigner = ed25519.utils.randomPrivateKey();
+  const bridgeSignerPublicKey = Array.from(ed25519.getPublicKey(bridgeSigner));
 
   // 定义全局变量
-  let vaultAccount: web3.Keypair;
-  let vaultAuthority: PublicKey;
   let mint: PublicKey;
   let userTokenAccount: PublicKey;
+  let config: PublicKey;
+  let vault: PublicKey;
+  let vaultAuthority: PublicKey;
   let vaultTokenAccount: PublicKey;
+  let bridgeVault: PublicKey;
   const depositAmount = 1000; // 定义一个测试存款金额
 
+  const pda = (...seeds: Buffer[]) => PublicKey.findProgramAddressSync(seeds, program.programId)[0];
+
   before(async () => {
     // 创建 mint 代币
     mint = await createMint(
       provider.connection,
-      provider.wallet.payer,
+      payer,
       provider.wallet.publicKey,
       null,
       9 // 小数位数
     );
 
     // 创建用户的 Token 账户
-    userTokenAccount = await createAccount(
-      provider.connection,
-      provider.wallet.payer,
-      mint,
-      provider.wallet.publicKey
-    );
+    userTokenAccount = await createAccount(provider.connection, payer, mint, provider.wallet.publicKey);
 
     // 给用户的账户铸造一些代币
     await mintTo(
       provider.connection,
-      provider.wallet.payer,
+      payer,
       mint,
       userTokenAccount,
       provider.wallet.publicKey,
       depositAmount * 10 // 赋予用户账户更多余额
     );
 
-    // 创建 Vault 账户
-    vaultAccount = web3.Keypair.generate();
-    vaultAuthority = await PublicKey.createProgramAddress(
-      [vaultAccount.publicKey.toBuffer()],
-      program.programId
-    );
-
-    // 创建 Vault 的 Token 账户
-    vaultTokenAccount = await createAccount(
-      provider.connection,
-      provider.wallet.payer,
-      mint,
-      vaultAuthority
-    );
+    config = pda(Buffer.from("config"));
+    vault = pda(Buffer.from("vault"), provider.wallet.publicKey.toBuffer(), mint.toBuffer());
+    vaultAuthority = pda(Buffer.from("authority"), vault.toBuffer());
+    vaultTokenAccount = pda(Buffer.from("tokens"), vault.toBuffer());
+    bridgeVault = pda(Buffer.from("bridge_vault"), vault.toBuffer());
   });
 
-  it("Initializes the Vault", async () => {
-    await program.methods
-      .initializeVault()
-      .accounts({
-        vault: vaultAccount.publicKey,
-        authority: provider.wallet.publicKey,
+  const setPaused = (paused: boolean) =>
+    program.methods.setPaused(paused).accountsPartial({ admin: provider.wallet.publicKey, config }).rpc();
+
+  const deposit = (amount: number) =>
+    program.methods
+      .deposit(new anchor.BN(amount))
+      .accountsPartial({
+        config,
+        owner: provider.wallet.publicKey,
+        ownerTokenAccount: userTokenAccount,
+        mint,
+        vault,
+        vaultAuthority,
         vaultTokenAccount,
+        tokenProgram: TOKEN_PROGRAM_ID,
+      })
+      .rpc();
+
+  const withdraw = (amount: number, nonce: number, signingKey: Uint8Array) => {
+    const expiry = Math.floor(Date.now() / 1000) + 3600;
+    const message = `${nonce}_${amount}_${userTokenAccount.toBase58()}_${expiry}`;
+    const signature = ed25519.sign(Buffer.from(message), signingKey);
+    const nonceBytes = new anchor.BN(nonce).toArrayLike(Buffer, "le", 8);
+    return program.methods
+      .withdraw(new anchor.BN(amount), new anchor.BN(nonce), new anchor.BN(expiry), [
+        { signer: Array.from(ed25519.getPublicKey(signingKey)), signature: Array.from(signature) },
+      ])
+      .accountsPartial({
+        config,
+        payer: provider.wallet.publicKey,
+        recipientTokenAccount: userTokenAccount,
         mint,
-        systemProgram: SystemProgram.programId,
+        vault,
+        bridgeVault,
+        vaultAuthority,
+        vaultTokenAccount,
+        receipt: pda(Buffer.from("receipt"), nonceBytes),
         tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
       })
-      .signers([vaultAccount])
       .rpc();
+  };
 
-    // 检查 Vault 账户是否成功初始化
-    const vaultAccountInfo = await provider.connection.getAccountInfo(vaultAccount.publicKey);
-    assert.ok(vaultAccountInfo !== null, "Vault account should be initialized");
+  const tokenBalance = async (account: PublicKey) =>
+    Number((await getAccount(provider.connection, account)).amount);
+
+  it("Initializes the config", async () => {
+    const [programData] = PublicKey.findProgramAddressSync(
+      [program.programId.toBuffer()],
+      BPF_LOADER_UPGRADEABLE_ID
+    );
+    await program.methods
+      .initializeConfig(provider.wallet.publicKey, [bridgeSignerPublicKey], 1)
+      .accountsPartial({
+        authority: provider.wallet.publicKey,
+        config,
+        program: program.programId,
+        programData,
+        systemProgram: SystemProgram.programId,
+      })
+      .rpc();
+
+    const configAccount = await program.account.config.fetch(config);
+    assert.ok(configAccount.admin.equals(provider.wallet.publicKey));
+    assert.strictEqual(configAccount.threshold, 1);
+    assert.strictEqual(configAccount.paused, false);
   });
 
-  it("Deposits tokens into the Vault", async () => {
+  it("Initializes the Vault", async () => {
     await program.methods
-      .deposit(new anchor.BN(depositAmount))
-      .accounts({
-        vault: vaultAccount.publicKey,
+      .initializeVault(new anchor.BN(depositAmount))
+      .accountsPartial({
+        owner: provider.wallet.publicKey,
+        mint,
+        ownerTokenAccount: userTokenAccount,
+        vault,
+        vaultAuthority,
         vaultTokenAccount,
-        userTokenAccount,
-        userAuthority: provider.wallet.publicKey,
         tokenProgram: TOKEN_PROGRAM_ID,
+        systemProgram: SystemProgram.programId,
+        rent: SYSVAR_RENT_PUBKEY,
       })
       .rpc();
 
+    // 检查 Vault 账户是否成功初始化
+    const vaultAccount = await program.account.vault.fetch(vault);
+    assert.ok(vaultAccount.initialized, "Vault account should be initialized");
+    assert.strictEqual(await tokenBalance(vaultTokenAccount), depositAmount);
+  });
+
+  it("Deposits tokens into the Vault", async () => {
+    await deposit(depositAmount);
+
     // 验证 Vault Token 账户的余额是否更新
-    const vaultTokenAccountInfo = await getAccount(provider.connection, vaultTokenAccount);
     assert.strictEqual(
-      vaultTokenAccountInfo.amount.toNumber(),
-      depositAmount,
+      await tokenBalance(vaultTokenAccount),
+      depositAmount * 2,
       "Vault should hold the deposited tokens"
     );
   });
 
-  it("Withdraws tokens from the Vault", async () => {
+  it("Rejects deposits while the bridge is paused", async () => {
+    await setPaused(true);
+    try {
+      await expectError(deposit(depositAmount), "BridgePaused");
+    } finally {
+      await setPaused(false);
+    }
+    assert.strictEqual(await tokenBalance(vaultTokenAccount), depositAmount * 2);
+  });
+
+  it("Registers the Vault with the bridge", async () => {
     await program.methods
-      .withdraw(new anchor.BN(depositAmount))
-      .accounts({
-        vault: vaultAccount.publicKey,
-        vaultTokenAccount,
-        userTokenAccount,
-        userAuthority: provider.wallet.publicKey,
-        tokenProgram: TOKEN_PROGRAM_ID,
+      .registerVault()
+      .accountsPartial({
+        admin: provider.wallet.publicKey,
+        payer: provider.wallet.publicKey,
+        config,
+        vault,
+        bridgeVault,
+        systemProgram: SystemProgram.programId,
       })
       .rpc();
 
+    const registration = await program.account.bridgeVault.fetch(bridgeVault);
+    assert.ok(registration.vault.equals(vault));
+  });
+
+  it("Rejects withdrawals signed by an unknown key", async () => {
+    await expectError(withdraw(depositAmount, 1, ed25519.utils.randomPrivateKey()), "InsufficientSignatures");
+  });
+
+  it("Rejects withdrawals while the bridge is paused", async () => {
+    await setPaused(true);
+    try {
+      await expectError(withdraw(depositAmount, 1, bridgeSigner), "BridgePaused");
+    } finally {
+      await setPaused(false);
+    }
+  });
+
+  it("Withdraws tokens from the Vault", async () => {
+    await withdraw(depositAmount * 2, 1, bridgeSigner);
+
     // 验证用户 Token 账户是否收到取款金额
-    const userTokenAccountInfo = await getAccount(provider.connection, userTokenAccount);
     assert.strictEqual(
-      userTokenAccountInfo.amount.toNumber(),
+      await tokenBalance(userTokenAccount),
       depositAmount * 10,
       "User should receive the withdrawn tokens"
     );
 
     // 验证 Vault Token 账户的余额是否归零
-    const vaultTokenAccountInfo = await getAccount(provider.connection, vaultTokenAccount);
-    assert.strictEqual(vaultTokenAccountInfo.amount.toNumber(), 0, "Vault should be empty after withdrawal");
+    assert.strictEqual(await tokenBalance(vaultTokenAccount), 0, "Vault should be empty after withdrawal");
+
+    const nonceBytes = new anchor.BN(1).toArrayLike(Buffer, "le", 8);
+    const receipt = await program.account.receipt.fetch(pda(Buffer.from("receipt"), nonceBytes));
+    assert.strictEqual(receipt.amount.toNumber(), depositAmount * 2);
+  });
+
+  it("Rejects a second withdrawal with the same nonce", async () => {
+    await deposit(depositAmount);
+    let error: unknown;
+    try {
+      await withdraw(depositAmount, 1, bridgeSigner);
+    } catch (err) {
+      error = err;
+    }
+    // the receipt PDA already exists, so its `init` fails before the program runs
+    assert.ok(error, "a nonce must only be redeemed once");
+    assert.strictEqual(await tokenBalance(vaultTokenAccount), depositAmount);
   });
 });